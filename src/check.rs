@@ -0,0 +1,167 @@
+use std::{fmt, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    config::{Config, MissingRepoAction},
+    git::get_repo_remotes,
+};
+
+/// Problems found in a watcher's config file by `watchers config check`.
+#[derive(Debug, Serialize)]
+pub struct ConfigReport {
+    pub name: String,
+    pub path: PathBuf,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+    /// The watched directories that exist, empty if the config couldn't be loaded
+    pub repos: Vec<RepoReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RepoReport {
+    pub path: PathBuf,
+    pub git_repo: bool,
+    pub remotes: Vec<String>,
+}
+
+impl ConfigReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+pub fn check_config(name: &str) -> ConfigReport {
+    let path = Config::get_watcher_config_path(name);
+    let mut report = ConfigReport {
+        name: name.to_string(),
+        path: path.clone(),
+        errors: Vec::new(),
+        warnings: Vec::new(),
+        repos: Vec::new(),
+    };
+
+    let (config, unknown_keys) = match Config::load(&path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            report.errors.push(format!("{:#}", e));
+            return report;
+        }
+    };
+
+    for key in unknown_keys {
+        report.errors.push(format!("Unknown key '{}'", key));
+    }
+
+    if config.commit_delay_secs == 0 {
+        report
+            .warnings
+            .push("commit_delay_secs is 0, so every single change is committed".to_string());
+    }
+
+    if let Some(max_interval) = config.max_commit_interval_secs
+        && max_interval < config.commit_delay_secs
+    {
+        report.warnings.push(format!(
+            "max_commit_interval_secs ({}) is shorter than commit_delay_secs ({}), \
+             so changes are committed after {} seconds",
+            max_interval, config.commit_delay_secs, max_interval
+        ));
+    }
+
+    for dir in &config.watch_dirs {
+        if !dir.is_dir() && config.on_missing_repo == MissingRepoAction::Clone && !dir.exists() {
+            report.warnings.push(format!(
+                "'{}' doesn't exist yet, it is cloned when the watcher starts",
+                dir.display()
+            ));
+        } else if !dir.is_dir() {
+            report.errors.push(format!(
+                "watch_dir '{}' doesn't exist or isn't a directory",
+                dir.display()
+            ));
+        }
+    }
+
+    let repo_dirs = config.repo_dirs();
+    if config.discover_repos && repo_dirs.is_empty() {
+        report
+            .warnings
+            .push("discover_repos is enabled but no repositories were found".to_string());
+    }
+
+    for dir in repo_dirs.iter().filter(|dir| dir.is_dir()) {
+        let remotes = match get_repo_remotes(&config, dir) {
+            Ok(remotes) => remotes,
+            Err(e) => {
+                report.warnings.push(format!(
+                    "Failed to read the remotes of '{}': {:#}",
+                    dir.display(),
+                    e
+                ));
+                continue;
+            }
+        };
+
+        match &remotes {
+            Some(remotes) if remotes.is_empty() && config.pushes() => {
+                let setting = match config.autosave_ref {
+                    Some(_) => "autosave_push",
+                    None => "auto_push",
+                };
+                report.warnings.push(format!(
+                    "{} is enabled but '{}' has no remote",
+                    setting,
+                    dir.display()
+                ))
+            }
+            Some(_) => (),
+            None => match config.on_missing_repo {
+                MissingRepoAction::Init => report.warnings.push(format!(
+                    "'{}' isn't a git repository yet, one is created on the first change",
+                    dir.display()
+                )),
+                MissingRepoAction::Error => report.errors.push(format!(
+                    "'{}' isn't a git repository and on_missing_repo is 'error'",
+                    dir.display()
+                )),
+                MissingRepoAction::Clone => report.warnings.push(format!(
+                    "'{}' isn't a git repository yet, it is cloned when the watcher starts",
+                    dir.display()
+                )),
+            },
+        }
+
+        report.repos.push(RepoReport {
+            path: dir.clone(),
+            git_repo: remotes.is_some(),
+            remotes: remotes.unwrap_or_default(),
+        });
+    }
+
+    report
+}
+
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = if self.is_valid() { "ok" } else { "invalid" };
+        writeln!(f, "{} ({}): {}", self.name, self.path.display(), result)?;
+
+        for e in &self.errors {
+            writeln!(f, "  error: {}", e)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "  warning: {}", warning)?;
+        }
+        for repo in self.repos.iter().filter(|r| !r.remotes.is_empty()) {
+            writeln!(
+                f,
+                "  {}: remotes {}",
+                repo.path.display(),
+                repo.remotes.join(", ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,11 +1,28 @@
+use std::{path::PathBuf, time::Duration};
+
 use clap::{Parser, Subcommand};
 
+use crate::output::OutputFormat;
+
 #[derive(Parser)]
 #[command(name = "watchers", version)]
 #[command(about = "File watcher manager", long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// How to print results and errors
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub output: OutputFormat,
+}
+
+impl Cli {
+    pub fn output_format(&self) -> OutputFormat {
+        match self.command {
+            Commands::Status { json: true, .. } => OutputFormat::Json,
+            _ => self.output,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -14,16 +31,99 @@ pub enum Commands {
 
     Stop { name: String },
 
-    Create { name: String },
+    /// Create a watcher and start it. Prompts for anything not given as a flag
+    /// when stdin is a terminal
+    Create {
+        name: String,
+        /// Directory to watch, can be given more than once
+        #[arg(long = "path")]
+        paths: Vec<PathBuf>,
+        /// Seconds to wait after the last change before committing
+        #[arg(long)]
+        delay: Option<u32>,
+        /// Create a git repository in the directory if it isn't one
+        #[arg(long)]
+        init: bool,
+        /// Clone this repository into the directory if it isn't one
+        #[arg(long, conflicts_with = "init")]
+        clone_url: Option<String>,
+        /// Don't push commits
+        #[arg(long)]
+        no_push: bool,
+        /// Overwrite an existing watcher with the same name
+        #[arg(long)]
+        force: bool,
+        /// Only write the config, don't start the service
+        #[arg(long)]
+        no_start: bool,
+    },
 
     Delete { name: String },
 
+    /// Edit a watcher's config in $EDITOR and apply it
+    Edit { name: String },
+
     Logs { name: String },
 
     List {},
 
+    /// Show the service, repository and push state of watchers
+    Status {
+        /// Only show this watcher
+        name: Option<String>,
+        /// Same as `--output json`
+        #[arg(long)]
+        json: bool,
+    },
+
     Trigger {name: String },
 
+    /// Keep watching but stop committing until resumed
+    Pause {
+        name: String,
+        /// Resume automatically after this long, e.g. 30m or 1h
+        #[arg(long = "for", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+
+    /// Resume committing and commit any changes made while paused
+    Resume { name: String },
+
+    /// Combine the watcher's latest autosave commits into one commit
+    Squash {
+        name: String,
+        /// Only squash commits made after this revision
+        #[arg(long)]
+        since: Option<String>,
+        /// Message of the new commit, defaults to a summary of the combined changes
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Also squash commits that were already pushed
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Make a running watcher re-read its config file
+    Reload { name: String },
+
+    /// Validate config files or print their schema
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
     #[command(hide = true, name = "__daemon")]
     Daemon { name: String }
 }
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Check watcher config files for unknown keys and other mistakes
+    Check {
+        /// Only check this watcher
+        name: Option<String>,
+    },
+
+    /// Print the JSON Schema of the config file format
+    Schema,
+}
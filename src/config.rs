@@ -1,30 +1,187 @@
 use std::{
     fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
 use directories::ProjectDirs;
+use log::warn;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::{discover::find_repos, filter::PathFilter, template::Template};
 
 fn default_true() -> bool {
     true
 }
 
-#[derive(Debug, Deserialize)]
+fn default_command_timeout_secs() -> u32 {
+    10
+}
+
+fn default_commit_delay_secs() -> u32 {
+    60
+}
+
+/// Files in the config directory holding settings shared by every watcher,
+/// in order of precedence. Their names can't be used for watchers.
+pub const DEFAULTS_FILE_NAMES: &[&str] = &["defaults", "config"];
+
+/// Which implementation is used to talk to git.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GitBackendKind {
+    /// Runs the `git` executable
+    #[default]
+    Cli,
+    /// Uses libgit2 in-process, requires the `native-git` feature
+    Native,
+}
+
+/// What to do when a watched directory isn't a git repository.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingRepoAction {
+    /// Run `git init` in it
+    #[default]
+    Init,
+    /// Refuse to watch it
+    Error,
+    /// Clone `clone_url` into it
+    Clone,
+}
+
+/// How the checked out branch catches up with its upstream before a push.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncStrategy {
+    /// Rebase the new commits onto the upstream branch
+    #[default]
+    Rebase,
+    /// Merge the upstream branch
+    Merge,
+    /// Only fast-forward, don't push if the branches have diverged
+    FfOnly,
+    /// Push without fetching first
+    SkipPull,
+    /// Rebase, and push to a `watchers/conflict-<host>-<time>` branch if that conflicts
+    AutosaveConflictsToBranch,
+}
+
+/// What `commit_message_command` receives on stdin.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitMessageInput {
+    #[default]
+    Diff,
+    Json,
+}
+
+/// The format of a watcher's YAML config file.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[schemars(title = "watchers config")]
 struct ConfigFile {
-    pub watch_dir: PathBuf,
+    /// Written by `watchers create`, the watcher is always named after the file
+    #[serde(default, rename = "name")]
+    _name: Option<String>,
+    /// Directory to watch and commit changes in
+    pub watch_dir: Option<PathBuf>,
+    /// More directories to watch, each committed to its own repository
+    #[serde(default)]
+    pub watch_dirs: Vec<PathBuf>,
+    /// Treat the watched directories as folders of repositories and commit to
+    /// every repository found beneath them instead
+    #[serde(default)]
+    pub discover_repos: bool,
+    /// What to do when a watched directory isn't a git repository
+    #[serde(default)]
+    pub on_missing_repo: MissingRepoAction,
+    /// Repository to clone with `on_missing_repo: clone`
+    pub clone_url: Option<String>,
+    /// Seconds to wait after the last change before committing
+    #[serde(default = "default_commit_delay_secs")]
     pub commit_delay_secs: u32,
+    /// Commit at least this often while changes keep coming in, no matter
+    /// how short the pauses between them are
+    pub max_commit_interval_secs: Option<u32>,
+    /// Push after every commit
     #[serde(default = "default_true")]
     pub auto_push: bool,
+    /// How to catch up with the upstream branch before pushing
+    #[serde(default)]
+    pub sync_strategy: SyncStrategy,
+    /// Record snapshots on this ref instead of committing to the checked out
+    /// branch, e.g. `refs/watchers/{branch}` or `wip/{branch}`
+    pub autosave_ref: Option<String>,
+    /// Push `autosave_ref` after every snapshot
+    #[serde(default)]
+    pub autosave_push: bool,
+    /// Only commit paths matching one of these globs
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Never commit paths matching one of these globs
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Template for commit messages, see the README for placeholders
+    pub commit_message_template: Option<String>,
+    /// Shell command that prints the commit message
+    pub commit_message_command: Option<String>,
+    #[serde(default)]
+    pub commit_message_command_input: CommitMessageInput,
+    #[serde(default = "default_command_timeout_secs")]
+    pub commit_message_command_timeout_secs: u32,
+    #[serde(default)]
+    pub git_backend: GitBackendKind,
+}
+
+/// The config file written by `watchers create`. Settings that weren't given
+/// are left out so they keep following the defaults file.
+#[derive(Serialize)]
+pub struct NewConfigFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub watch_dirs: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_missing_repo: Option<MissingRepoAction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clone_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_delay_secs: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_push: Option<bool>,
 }
 
 #[derive(Clone, Serialize)]
 pub struct Config {
     pub name: String,
-    pub watch_dir: PathBuf,
+    /// `watch_dir` followed by `watch_dirs`, never empty
+    pub watch_dirs: Vec<PathBuf>,
+    pub discover_repos: bool,
+    pub on_missing_repo: MissingRepoAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clone_url: Option<String>,
     pub commit_delay_secs: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_commit_interval_secs: Option<u32>,
     pub auto_push: bool,
+    pub sync_strategy: SyncStrategy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autosave_ref: Option<String>,
+    pub autosave_push: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_message_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_message_command: Option<String>,
+    pub commit_message_command_input: CommitMessageInput,
+    pub commit_message_command_timeout_secs: u32,
+    pub git_backend: GitBackendKind,
 }
 
 pub fn get_watchers_config_dir() -> PathBuf {
@@ -32,16 +189,44 @@ pub fn get_watchers_config_dir() -> PathBuf {
     proj_dir.config_dir().to_path_buf()
 }
 
-impl Config {
-    pub fn new(name: impl Into<String>, path: impl AsRef<Path>) -> Config {
-        Config {
-            name: name.into(),
-            watch_dir: path.as_ref().to_path_buf(),
-            commit_delay_secs: 60,
-            auto_push: true,
-        }
+/// The file with settings shared by all watchers, if there is one.
+pub fn get_defaults_path() -> Option<PathBuf> {
+    let config_dir = get_watchers_config_dir();
+    DEFAULTS_FILE_NAMES
+        .iter()
+        .map(|name| config_dir.join(format!("{}.yml", name)))
+        .find(|path| path.is_file())
+}
+
+fn read_mapping(path: &Path) -> Result<Mapping> {
+    let content = fs::read_to_string(path)?;
+    match serde_yaml::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?
+    {
+        Value::Mapping(mapping) => Ok(mapping),
+        Value::Null => Ok(Mapping::new()),
+        _ => anyhow::bail!("{} should contain a mapping of settings", path.display()),
     }
+}
 
+pub fn get_watchers_state_dir() -> PathBuf {
+    let proj_dir = ProjectDirs::from("", "", "watchers").unwrap();
+    proj_dir
+        .state_dir()
+        .unwrap_or(proj_dir.data_local_dir())
+        .to_path_buf()
+}
+
+pub fn get_watchers_runtime_dir() -> PathBuf {
+    let proj_dir = ProjectDirs::from("", "", "watchers").unwrap();
+    // $XDG_RUNTIME_DIR isn't always set, e.g. outside of a login session
+    proj_dir
+        .runtime_dir()
+        .unwrap_or(proj_dir.cache_dir())
+        .to_path_buf()
+}
+
+impl Config {
     pub fn get_watcher_config_path(name: &str) -> PathBuf {
         let config_dir = get_watchers_config_dir();
         config_dir.join(format!("{}.yml", name))
@@ -49,24 +234,148 @@ impl Config {
 
     pub fn from_file<P: AsRef<Path>>(config_path: P) -> Result<Config> {
         let path = config_path.as_ref();
+        let (config, unknown_keys) = Config::load(path)?;
+        for key in unknown_keys {
+            warn!("Ignoring unknown key '{}' in {}", key, path.display());
+        }
+        Ok(config)
+    }
+
+    /// Like `from_file`, but also returns the keys in the file that aren't
+    /// part of the config format instead of ignoring them.
+    pub fn load(path: &Path) -> Result<(Config, Vec<String>)> {
         let name = path
             .file_stem()
             .and_then(|s| s.to_str())
-            .ok_or_else(|| anyhow::anyhow!("Invalid config filename"))?
-            .to_string();
+            .ok_or_else(|| anyhow::anyhow!("Invalid config filename"))?;
+        Config::load_as(name, path)
+    }
+
+    /// Like `load` for a file that isn't named after the watcher, e.g. a copy being edited.
+    pub fn load_as(name: &str, path: &Path) -> Result<(Config, Vec<String>)> {
+        let name = name.to_string();
+
+        // settings in the watcher's own file override the defaults file
+        let mut values = match get_defaults_path() {
+            Some(defaults_path) => read_mapping(&defaults_path)?,
+            None => Mapping::new(),
+        };
+        values.extend(read_mapping(path)?);
+
+        let mut unknown_keys = Vec::new();
+        let user_config: ConfigFile = serde_ignored::deserialize(Value::Mapping(values), |key| {
+            unknown_keys.push(key.to_string())
+        })
+        .context("Failed to load config")?;
+
+        let mut watch_dirs = Vec::new();
+        for dir in user_config
+            .watch_dir
+            .into_iter()
+            .chain(user_config.watch_dirs)
+        {
+            let dir = PathBuf::from(shellexpand::tilde(&dir.to_string_lossy()).to_string());
+            if !watch_dirs.contains(&dir) {
+                watch_dirs.push(dir);
+            }
+        }
+        anyhow::ensure!(
+            !watch_dirs.is_empty(),
+            "Either watch_dir or watch_dirs has to be set"
+        );
 
-        let content = fs::read_to_string(config_path)?;
-        let user_config: ConfigFile =
-            serde_yaml::from_str(&content).context("Failed to load config")?;
-        Ok(Config {
+        let config = Config {
             name,
-            watch_dir: user_config.watch_dir,
+            watch_dirs,
+            discover_repos: user_config.discover_repos,
+            on_missing_repo: user_config.on_missing_repo,
+            clone_url: user_config.clone_url,
             commit_delay_secs: user_config.commit_delay_secs,
+            max_commit_interval_secs: user_config.max_commit_interval_secs,
             auto_push: user_config.auto_push,
-        })
+            sync_strategy: user_config.sync_strategy,
+            autosave_ref: user_config.autosave_ref,
+            autosave_push: user_config.autosave_push,
+            include: user_config.include,
+            exclude: user_config.exclude,
+            commit_message_template: user_config.commit_message_template,
+            commit_message_command: user_config.commit_message_command,
+            commit_message_command_input: user_config.commit_message_command_input,
+            commit_message_command_timeout_secs: user_config.commit_message_command_timeout_secs,
+            git_backend: user_config.git_backend,
+        };
+
+        #[cfg(not(feature = "native-git"))]
+        anyhow::ensure!(
+            config.git_backend != GitBackendKind::Native,
+            "git_backend 'native' requires watchers to be built with the 'native-git' feature"
+        );
+
+        if config.on_missing_repo == MissingRepoAction::Clone {
+            anyhow::ensure!(
+                config.clone_url.is_some(),
+                "on_missing_repo 'clone' needs a clone_url"
+            );
+            anyhow::ensure!(
+                config.watch_dirs.len() == 1 && !config.discover_repos,
+                "on_missing_repo 'clone' only works with a single watch_dir"
+            );
+        }
+
+        // surface bad globs and templates when loading rather than on the first event
+        config.path_filter()?;
+        if let Some(template) = &config.commit_message_template {
+            Template::parse(template)?;
+        }
+        if let Some(autosave_ref) = &config.autosave_ref {
+            anyhow::ensure!(
+                !autosave_ref.trim().is_empty() && !autosave_ref.contains(char::is_whitespace),
+                "autosave_ref '{}' isn't a valid ref name",
+                autosave_ref
+            );
+        }
+        Ok((config, unknown_keys))
+    }
+
+    /// The repositories to commit to: the watched directories themselves, or
+    /// the repositories found beneath them with `discover_repos`
+    pub fn repo_dirs(&self) -> Vec<PathBuf> {
+        if !self.discover_repos {
+            return self.watch_dirs.clone();
+        }
+        self.watch_dirs
+            .iter()
+            .flat_map(|root| find_repos(root))
+            .collect()
+    }
+
+    pub fn commit_delay(&self) -> Duration {
+        Duration::from_secs(self.commit_delay_secs as u64)
+    }
+
+    pub fn max_commit_interval(&self) -> Option<Duration> {
+        self.max_commit_interval_secs
+            .map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    /// Whether anything is pushed, which is `autosave_push` when saving to `autosave_ref`
+    pub fn pushes(&self) -> bool {
+        match self.autosave_ref {
+            Some(_) => self.autosave_push,
+            None => self.auto_push,
+        }
+    }
+
+    pub fn path_filter(&self) -> Result<PathFilter> {
+        PathFilter::new(&self.include, &self.exclude)
     }
 
     pub fn dump(&self) -> serde_yaml::Result<String> {
         serde_yaml::to_string(self)
     }
 }
+
+/// JSON Schema of the config file format, for editor completion.
+pub fn config_schema() -> schemars::Schema {
+    schemars::schema_for!(ConfigFile)
+}
@@ -0,0 +1,187 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{config::get_watchers_runtime_dir, output::ErrorCode, watcher::WatchMessage};
+
+/// A request sent to a running daemon over its control socket. Requests and
+/// responses are single lines of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum Request {
+    Trigger,
+    Pause { duration_secs: Option<u64> },
+    Resume,
+    Status,
+    ReloadConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Status(DaemonStatus),
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub name: String,
+    pub watch_dirs: Vec<PathBuf>,
+    pub paused: bool,
+    /// Unix time the pause runs out at, if it was paused with a duration
+    pub paused_until: Option<u64>,
+    /// Whether changes in any directory are waiting for the commit delay to pass
+    pub commit_pending: bool,
+}
+
+impl Response {
+    pub fn error(e: anyhow::Error) -> Self {
+        Response::Error {
+            message: format!("{:#}", e),
+        }
+    }
+
+    pub fn into_result(self) -> Result<Self> {
+        match self {
+            Response::Error { message } => Err(anyhow::anyhow!(message)),
+            response => Ok(response),
+        }
+    }
+}
+
+impl Request {
+    pub fn pause(duration: Option<Duration>) -> Self {
+        Request::Pause {
+            duration_secs: duration.map(|d| d.as_secs()),
+        }
+    }
+
+    /// How long to wait for the response, longer for requests that wait for commits
+    fn timeout(&self) -> Duration {
+        match self {
+            Request::Trigger | Request::Resume => Duration::from_secs(300),
+            _ => Duration::from_secs(10),
+        }
+    }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn get_socket_path(name: &str) -> PathBuf {
+    get_watchers_runtime_dir().join(format!("{}.sock", name))
+}
+
+pub fn unix_secs(time: std::time::SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+pub async fn is_running(name: &str) -> bool {
+    time::timeout(CONNECT_TIMEOUT, UnixStream::connect(get_socket_path(name)))
+        .await
+        .is_ok_and(|stream| stream.is_ok())
+}
+
+/// Sends `request` to the daemon and waits for its response, failing if the
+/// daemon doesn't respond in time, e.g. because it hangs.
+pub async fn send_request(name: &str, request: &Request) -> Result<Response> {
+    let stream = time::timeout(CONNECT_TIMEOUT, UnixStream::connect(get_socket_path(name)))
+        .await
+        .map_err(|_| ErrorCode::NotRunning.err(format!("Watcher '{}' is not responding", name)))?
+        .map_err(|_| ErrorCode::NotRunning.err(format!("Watcher '{}' is not running", name)))?;
+
+    let timeout = request.timeout();
+    time::timeout(timeout, exchange(stream, request))
+        .await
+        .map_err(|_| {
+            ErrorCode::Other.err(format!(
+                "Watcher '{}' didn't respond within {} seconds",
+                name,
+                timeout.as_secs()
+            ))
+        })?
+}
+
+async fn exchange(stream: UnixStream, request: &Request) -> Result<Response> {
+    let (read, mut write) = stream.into_split();
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let mut line = String::new();
+    BufReader::new(read)
+        .read_line(&mut line)
+        .await
+        .context("Failed to read response from daemon")?;
+    anyhow::ensure!(!line.is_empty(), "Daemon closed the connection");
+
+    serde_json::from_str(&line).context("Invalid response from daemon")
+}
+
+/// Listens on the control socket and forwards requests to the watch loop.
+pub async fn serve(name: &str, tx: mpsc::UnboundedSender<WatchMessage>) -> Result<()> {
+    let path = get_socket_path(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // a socket left behind by a daemon that didn't shut down cleanly
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind control socket: {}", path.display()))?;
+    debug!("listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, tx).await {
+                error!("Control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    tx: mpsc::UnboundedSender<WatchMessage>,
+) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    // `is_running` connects without sending anything
+    if line.is_empty() {
+        return Ok(());
+    }
+
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            debug!("got control request: {:?}", request);
+            let (reply_tx, reply_rx) = oneshot::channel();
+            tx.send(WatchMessage::Control(request, reply_tx))
+                .map_err(|_| anyhow::anyhow!("Watch loop has stopped"))?;
+            reply_rx.await?
+        }
+        Err(e) => Response::error(anyhow::anyhow!("Invalid request: {}", e)),
+    };
+
+    let mut line = serde_json::to_string(&response)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    Ok(())
+}
@@ -1,89 +1,179 @@
 use std::{
-    sync::{Arc, Condvar, Mutex},
-    thread::{self, JoinHandle},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
     time::Duration,
 };
 
-use log::debug;
+use log::{debug, error};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{Instant, sleep_until},
+};
 
 use crate::git::EventContext;
 
-pub struct Debouncer<F> {
-    callback: Arc<Mutex<F>>,
-    delay: Duration,
-    cancel_signal: Arc<(Mutex<bool>, Condvar)>,
-    current_thread: Option<JoinHandle<()>>,
-    pending_context: Arc<Mutex<Option<EventContext>>>,
+enum Message {
+    Event(EventContext),
+    /// Run the callback right away and reply once it has finished
+    Run(EventContext, oneshot::Sender<()>),
+    Cancel,
+    SetTiming {
+        delay: Duration,
+        max_wait: Option<Duration>,
+    },
 }
 
-impl<F> Debouncer<F>
-where
-    F: FnMut(EventContext) + Send + 'static,
-{
-    pub fn new(callback: F, delay: Duration) -> Self {
-        Self {
+/// The latest event waiting for the callback
+struct Pending {
+    context: EventContext,
+    /// When the first of the events it stands for arrived
+    since: Instant,
+    last_event: Instant,
+}
+
+/// Runs the callback once events stop arriving for `delay`. The timer lives
+/// in a single task per debouncer, which stops when the debouncer is dropped.
+pub struct Debouncer {
+    tx: mpsc::UnboundedSender<Message>,
+    pending: Arc<AtomicBool>,
+}
+
+impl Debouncer {
+    /// Must be called from within the tokio runtime
+    pub fn new<F>(callback: F, delay: Duration, max_wait: Option<Duration>) -> Self
+    where
+        F: FnMut(EventContext) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicBool::new(false));
+        let timer = Timer {
             callback: Arc::new(Mutex::new(callback)),
             delay,
-            cancel_signal: Arc::new((Mutex::new(false), Condvar::new())),
-            current_thread: None,
-            pending_context: Arc::new(Mutex::new(None)),
-        }
+            max_wait,
+            pending: Arc::clone(&pending),
+        };
+        tokio::spawn(timer.run(rx));
+        Self { tx, pending }
+    }
+
+    pub fn on_event(&self, context: EventContext) {
+        self.send(Message::Event(context));
     }
 
-    pub fn run_callback(&mut self, context: EventContext) {
-        self.cancel_current_thread();
+    /// Runs the callback now instead of waiting for the delay. The returned
+    /// receiver resolves once it has finished.
+    pub fn run_callback(&self, context: EventContext) -> oneshot::Receiver<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.send(Message::Run(context, done_tx));
+        done_rx
+    }
 
-        // scope cb def to drop lock before mutable self borrow below
-        let mut cb = self.callback.lock().unwrap();
-        cb(context.clone());
+    pub fn set_timing(&self, delay: Duration, max_wait: Option<Duration>) {
+        self.send(Message::SetTiming { delay, max_wait });
     }
 
-    pub fn on_event(&mut self, context: EventContext) {
-        *self.pending_context.lock().unwrap() = Some(context);
-        self.cancel_current_thread();
-        debug!("cancelled old thread");
+    /// Drops any pending event without running the callback
+    pub fn cancel(&self) {
+        self.send(Message::Cancel);
+    }
 
-        let callback = Arc::clone(&self.callback);
-        let pending_context = Arc::clone(&self.pending_context);
-        let cancel_signal = Arc::new((Mutex::new(false), Condvar::new()));
-        self.cancel_signal = Arc::clone(&cancel_signal);
-        let delay = self.delay;
-
-        let handle = thread::spawn(move || {
-            let (lock, cvar) = &*cancel_signal;
-            let mut cancelled = lock.lock().unwrap();
-
-            let result = cvar.wait_timeout(cancelled, delay).unwrap();
-            cancelled = result.0;
-
-            if (!*cancelled && result.1.timed_out())
-                && let (Ok(mut cb), Ok(mut context_guard)) =
-                    (callback.lock(), pending_context.lock())
-                && let Some(context) = context_guard.take()
-            {
-                cb(context);
-            }
-        });
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Relaxed)
+    }
 
-        self.current_thread = Some(handle);
+    fn send(&self, message: Message) {
+        // the timer task only stops when the debouncer is dropped
+        let _ = self.tx.send(message);
     }
 }
 
-impl<F> Debouncer<F> {
-    fn cancel_current_thread(&mut self) {
-        if self.current_thread.is_some() {
-            let (lock, cvar) = &*self.cancel_signal;
-            let mut cancelled = lock.lock().unwrap();
-            *cancelled = true;
-            cvar.notify_all();
+struct Timer<F> {
+    callback: Arc<Mutex<F>>,
+    delay: Duration,
+    /// Longest an event waits for the callback while newer ones keep arriving
+    max_wait: Option<Duration>,
+    pending: Arc<AtomicBool>,
+}
+
+impl<F> Timer<F>
+where
+    F: FnMut(EventContext) + Send + 'static,
+{
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Message>) {
+        let mut pending: Option<Pending> = None;
+
+        loop {
+            let message = match pending.as_ref().map(|p| self.deadline(p)) {
+                Some(deadline) => {
+                    tokio::select! {
+                        message = rx.recv() => message,
+                        _ = sleep_until(deadline) => {
+                            if let Some(p) = pending.take() {
+                                self.pending.store(false, Ordering::Relaxed);
+                                self.call(p.context).await;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => rx.recv().await,
+            };
 
-            let _old_handle = self.current_thread.take();
+            // the debouncer was dropped, along with anything still pending
+            let Some(message) = message else {
+                break;
+            };
+
+            match message {
+                Message::Event(context) => {
+                    let now = Instant::now();
+                    let since = pending.as_ref().map_or(now, |p| p.since);
+                    pending = Some(Pending {
+                        context,
+                        since,
+                        last_event: now,
+                    });
+                }
+                Message::Run(context, done) => {
+                    pending = None;
+                    self.pending.store(false, Ordering::Relaxed);
+                    self.call(context).await;
+                    let _ = done.send(());
+                }
+                Message::Cancel => pending = None,
+                Message::SetTiming { delay, max_wait } => {
+                    self.delay = delay;
+                    self.max_wait = max_wait;
+                }
+            }
+            self.pending.store(pending.is_some(), Ordering::Relaxed);
+        }
+    }
+
+    /// When the callback runs if no other event arrives, never later than the ceiling
+    fn deadline(&self, pending: &Pending) -> Instant {
+        let deadline = pending.last_event + self.delay;
+        match self.max_wait {
+            Some(max_wait) => deadline.min(pending.since + max_wait),
+            None => deadline,
         }
     }
-}
 
-impl<F> Drop for Debouncer<F> {
-    fn drop(&mut self) {
-        self.cancel_current_thread();
+    /// The callback commits through git, so it runs on the blocking pool. Events
+    /// arriving meanwhile wait in the channel, so calls never overlap.
+    async fn call(&self, context: EventContext) {
+        debug!("running debounced callback");
+        let callback = Arc::clone(&self.callback);
+        let result = tokio::task::spawn_blocking(move || {
+            // a panic in an earlier commit shouldn't stop later ones
+            let mut callback = callback.lock().unwrap_or_else(PoisonError::into_inner);
+            callback(context);
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Commit callback failed: {}", e);
+        }
     }
 }
@@ -0,0 +1,50 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+pub fn is_repo_root(dir: &Path) -> bool {
+    dir.join(".git").exists()
+}
+
+/// Finds the git repositories in `dir` and beneath it, without descending into
+/// repositories that were already found or following symlinks.
+pub fn find_repos(dir: &Path) -> Vec<PathBuf> {
+    let mut repos = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        if is_repo_root(&dir) {
+            repos.push(dir);
+            continue;
+        }
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("skipping {:?} while looking for repositories: {}", dir, e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pending.push(entry.path());
+            }
+        }
+    }
+
+    repos.sort();
+    repos
+}
+
+/// The outermost repository beneath `root` containing `path`, so that submodules
+/// and nested repositories belong to the one around them like in `find_repos`.
+pub fn find_enclosing_repo(root: &Path, path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .take_while(|dir| dir.starts_with(root))
+        .filter(|dir| dir.is_dir() && is_repo_root(dir))
+        .last()
+        .map(Path::to_path_buf)
+}
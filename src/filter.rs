@@ -0,0 +1,73 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Include/exclude glob patterns applied on top of `.gitignore`. Patterns are
+/// matched against paths relative to the watched directory.
+#[derive(Clone)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// The watched directory relative to the root of the paths given to `is_match`
+    prefix: PathBuf,
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).with_context(|| format!("Invalid glob '{}'", pattern))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(include)?)
+        };
+
+        Ok(Self {
+            include,
+            exclude: build_glob_set(exclude)?,
+            prefix: PathBuf::new(),
+        })
+    }
+
+    /// Matches paths relative to a repository whose subdirectory `prefix` is the
+    /// watched directory. Paths outside of it never match.
+    pub fn within(self, prefix: PathBuf) -> Self {
+        Self { prefix, ..self }
+    }
+
+    /// Whether every path matches
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty() && self.prefix.as_os_str().is_empty()
+    }
+
+    pub fn is_match(&self, rel_path: impl AsRef<Path>) -> bool {
+        let Ok(rel_path) = rel_path.as_ref().strip_prefix(&self.prefix) else {
+            return false;
+        };
+        if self.exclude.is_match(rel_path) {
+            return false;
+        }
+
+        match &self.include {
+            Some(include) => include.is_match(rel_path),
+            None => true,
+        }
+    }
+
+    /// Returns true if any of the absolute `paths` under `root` passes the filter.
+    /// Paths outside of `root` are let through unchanged.
+    pub fn matches_any(&self, root: &Path, paths: &[impl AsRef<Path>]) -> bool {
+        paths.iter().any(|p| match p.as_ref().strip_prefix(root) {
+            Ok(rel_path) => self.is_match(rel_path),
+            Err(_) => true,
+        })
+    }
+}
@@ -1,11 +1,38 @@
 use anyhow::{Context, Result};
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use serde::Serialize;
 use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::config::Config;
+mod cli;
+mod ignore_matcher;
+#[cfg(feature = "native-git")]
+mod native;
+mod squash;
+#[cfg(test)]
+mod tests;
+
+pub use ignore_matcher::IgnoreMatcher;
+pub use squash::{SquashOptions, SquashResult, squash_autosaves};
+
+use crate::{
+    config::{CommitMessageInput, Config, GitBackendKind, MissingRepoAction, SyncStrategy},
+    filter::PathFilter,
+    state::{CommitRecord, WatcherState},
+    template::Template,
+};
+
+/// Trailer added to every commit the watcher makes, so `watchers squash` can
+/// tell them apart from the user's own commits
+const AUTOSAVE_TRAILER: &str = "Watchers-Autosave";
 
 #[derive(Clone)]
 pub struct EventContext {
@@ -14,9 +41,20 @@ pub struct EventContext {
 }
 
 fn git(repo_path: &Path, args: &[&str]) -> Result<String> {
+    Ok(git_raw(repo_path, args)?.trim().to_string())
+}
+
+/// Like `git` but keeps leading whitespace, which is significant in porcelain output
+fn git_raw(repo_path: &Path, args: &[&str]) -> Result<String> {
+    git_raw_env(repo_path, &[], args)
+}
+
+/// Like `git_raw` with extra environment variables, e.g. `GIT_INDEX_FILE`
+fn git_raw_env(repo_path: &Path, envs: &[(&str, &Path)], args: &[&str]) -> Result<String> {
     let output = Command::new("git")
         .args(["-C", &repo_path.to_string_lossy()])
         .args(args)
+        .envs(envs.iter().copied())
         .output()
         .with_context(|| format!("Failed to run git {:?}", args))?;
 
@@ -25,130 +63,609 @@ fn git(repo_path: &Path, args: &[&str]) -> Result<String> {
         anyhow::bail!("git {} failed: {}", args[0], stderr.trim());
     }
 
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
 }
 
-fn open_or_create_repo(repo_path: &Path) -> Result<()> {
+fn open_or_create_repo(backend: &dyn GitBackend, config: &Config, repo_path: &Path) -> Result<()> {
     // Check if we're in a git repo
-    if git(repo_path, &["rev-parse", "--git-dir"]).is_err() {
-        git(repo_path, &["init"])?;
+    if backend.is_repo(repo_path) {
+        return Ok(());
+    }
+
+    match config.on_missing_repo {
+        MissingRepoAction::Init => backend.init(repo_path),
+        MissingRepoAction::Error => anyhow::bail!(
+            "{} isn't a git repository and on_missing_repo is 'error'",
+            repo_path.display()
+        ),
+        MissingRepoAction::Clone => {
+            let url = config
+                .clone_url
+                .as_deref()
+                .context("on_missing_repo 'clone' needs a clone_url")?;
+            info!("Cloning {} into {}", url, repo_path.display());
+            backend.clone(url, repo_path)
+        }
+    }
+}
+
+/// Whether `path` is inside a git repository
+pub fn is_repo(path: &Path) -> bool {
+    backend(GitBackendKind::default()).is_repo(path)
+}
+
+pub fn clone_repo(url: &str, path: &Path) -> Result<()> {
+    backend(GitBackendKind::default()).clone(url, path)
+}
+
+/// Makes sure the watched directories can be watched before the daemon starts:
+/// clones missing repositories and fails for ones that aren't allowed to be
+/// created. Initialising is left to the first change, as before.
+pub fn prepare_repos(config: &Config) -> Result<()> {
+    if config.discover_repos || config.on_missing_repo == MissingRepoAction::Init {
+        return Ok(());
+    }
+
+    let backend = backend(config.git_backend);
+    for dir in &config.watch_dirs {
+        open_or_create_repo(backend, config, dir)?;
     }
     Ok(())
 }
 
+#[derive(Serialize)]
+struct RenamedFile {
+    from: String,
+    to: String,
+}
+
+#[derive(Default, Serialize)]
 struct ChangedFiles {
     deleted: Vec<String>,
     modified: Vec<String>,
     added: Vec<String>,
+    renamed: Vec<RenamedFile>,
 }
 
 impl ChangedFiles {
     fn is_empty(&self) -> bool {
-        self.deleted.is_empty() && self.modified.is_empty() && self.added.is_empty()
+        self.deleted.is_empty()
+            && self.modified.is_empty()
+            && self.added.is_empty()
+            && self.renamed.is_empty()
     }
-}
 
-fn get_changed_files(repo_path: &Path) -> Result<ChangedFiles> {
-    // --porcelain gives stable, parseable output
-    let output = git(repo_path, &["status", "--porcelain"])?;
+    fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.deleted.retain(|f| keep(f));
+        self.modified.retain(|f| keep(f));
+        self.added.retain(|f| keep(f));
+        self.renamed.retain(|r| keep(&r.from) || keep(&r.to));
+    }
 
-    let mut deleted = Vec::new();
-    let mut modified = Vec::new();
-    let mut added = Vec::new();
+    /// Every path touched by the changes, including both sides of renames
+    fn paths(&self) -> impl Iterator<Item = &String> {
+        self.deleted
+            .iter()
+            .chain(self.modified.iter())
+            .chain(self.added.iter())
+            .chain(self.renamed.iter().flat_map(|r| [&r.from, &r.to]))
+    }
+}
 
-    for line in output.lines() {
-        if line.len() < 4 {
-            continue;
-        }
-        // Porcelain format: XY filename
-        // We care about the working tree status (second char) for unstaged changes
-        let xy = &line[..2];
-        let file = line[3..].to_string();
-
-        match xy {
-            // Deleted in worktree
-            " D" => deleted.push(file),
-            // Untracked (new) file
-            "??" => added.push(file),
-            // Modified in worktree, or any other status indicating a change
-            _ => modified.push(file),
-        }
+/// Operations the watcher needs from git. Every method takes the path of the
+/// repository (or a directory inside it) to act on.
+trait GitBackend: Send + Sync {
+    fn is_repo(&self, repo_path: &Path) -> bool;
+    /// The repository's `.git` directory
+    fn git_dir(&self, repo_path: &Path) -> Result<PathBuf>;
+    /// The top level of the working tree, a parent of `repo_path` if it is a subdirectory
+    fn workdir(&self, repo_path: &Path) -> Result<PathBuf>;
+    fn init(&self, repo_path: &Path) -> Result<()>;
+    fn clone(&self, url: &str, repo_path: &Path) -> Result<()>;
+    /// Changes in the working tree and index that haven't been committed yet
+    fn changed_files(&self, repo_path: &Path) -> Result<ChangedFiles>;
+    /// Changes staged in the index, with renames detected
+    fn staged_files(&self, repo_path: &Path) -> Result<ChangedFiles>;
+    fn stage_all(&self, repo_path: &Path) -> Result<()>;
+    fn stage_paths(&self, repo_path: &Path, paths: &[&str]) -> Result<()>;
+    fn staged_diff(&self, repo_path: &Path) -> Result<String>;
+    /// Commits the index and returns the new commit's hash
+    fn commit(&self, repo_path: &Path, message: &str) -> Result<String>;
+    fn fetch(&self, repo_path: &Path) -> Result<()>;
+    /// Name of the upstream branch, e.g. `origin/main`, if one is configured
+    fn upstream(&self, repo_path: &Path) -> Result<Option<String>>;
+    /// Rebases onto `upstream`, returns false after aborting if there were conflicts.
+    /// Any other failure is an error.
+    fn rebase(&self, repo_path: &Path, upstream: &str) -> Result<bool>;
+    /// Merges `upstream`, returns false after aborting if there were conflicts.
+    /// Any other failure is an error.
+    fn merge(&self, repo_path: &Path, upstream: &str) -> Result<bool>;
+    /// Fast-forwards to `upstream`, returns false if the branches have diverged
+    fn fast_forward(&self, repo_path: &Path, upstream: &str) -> Result<bool>;
+    fn push(&self, repo_path: &Path) -> Result<()>;
+    /// How many commits HEAD is ahead of and behind its upstream, if it has one
+    fn ahead_behind(&self, repo_path: &Path) -> Result<Option<(usize, usize)>>;
+    fn remotes(&self, repo_path: &Path) -> Result<Vec<String>>;
+    fn submodule_paths(&self, repo_path: &Path) -> Result<Vec<String>>;
+    /// Short name of the branch HEAD points at, None if HEAD is detached
+    fn current_branch(&self, repo_path: &Path) -> Result<Option<String>>;
+    /// Commit hash `rev` points at, None if it doesn't exist
+    fn resolve(&self, repo_path: &Path, rev: &str) -> Result<Option<String>>;
+    /// Writes the working tree as a tree object through a temporary index seeded
+    /// from `base`, so the repository's own index is left alone. Changes to paths
+    /// `keep` returns false for are left as they are in `base`.
+    fn snapshot_tree(
+        &self,
+        repo_path: &Path,
+        base: Option<&str>,
+        keep: &dyn Fn(&str) -> bool,
+    ) -> Result<String>;
+    /// Changes between the tree of commit `base` and `tree`, with renames detected
+    fn tree_changes(
+        &self,
+        repo_path: &Path,
+        base: Option<&str>,
+        tree: &str,
+    ) -> Result<ChangedFiles>;
+    fn tree_diff(&self, repo_path: &Path, base: Option<&str>, tree: &str) -> Result<String>;
+    /// Creates a commit without moving any ref and returns its hash
+    fn commit_tree(
+        &self,
+        repo_path: &Path,
+        tree: &str,
+        parent: Option<&str>,
+        message: &str,
+    ) -> Result<String>;
+    /// Points `refname` at `new`, failing if it no longer points at `old`
+    /// (or exists at all when `old` is None)
+    fn update_ref(
+        &self,
+        repo_path: &Path,
+        refname: &str,
+        new: &str,
+        old: Option<&str>,
+    ) -> Result<()>;
+    fn push_ref(&self, repo_path: &Path, remote: &str, refname: &str) -> Result<()>;
+}
+
+fn backend(kind: GitBackendKind) -> &'static dyn GitBackend {
+    match kind {
+        GitBackendKind::Cli => &cli::CliBackend,
+        #[cfg(feature = "native-git")]
+        GitBackendKind::Native => &native::NativeBackend,
+        // rejected when the config is loaded
+        #[cfg(not(feature = "native-git"))]
+        GitBackendKind::Native => &cli::CliBackend,
     }
+}
 
-    Ok(ChangedFiles {
-        deleted,
-        modified,
-        added,
-    })
+/// Locks the repository against other watchers changing it at the same time,
+/// e.g. the daemon and `watchers trigger` in another process, or a commit and
+/// the rebase before a push. The lock is released when the file is dropped.
+fn lock_repo(backend: &dyn GitBackend, repo_path: &Path) -> Result<File> {
+    let path = backend.git_dir(repo_path)?.join("watchers.lock");
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.lock()
+        .with_context(|| format!("Failed to lock {}", path.display()))?;
+    Ok(file)
 }
 
+/// Commits the changes in the repository and pushes them if configured. The
+/// daemon runs the two halves separately so pushing never delays a commit.
 pub fn handle_event(context: EventContext) {
-    if let Err(e) = open_or_create_repo(&context.repo_path) {
+    if commit_changes(&context) {
+        // a failed push stays in the outbox for the daemon to retry
+        push_changes(&context);
+    }
+}
+
+/// Commits (or snapshots) the changes in the repository while holding its lock,
+/// returning whether there is something to push.
+pub fn commit_changes(context: &EventContext) -> bool {
+    let backend = backend(context.config.git_backend);
+    if let Err(e) = open_or_create_repo(backend, &context.config, &context.repo_path) {
         error!("Failed to open repository: {}", e);
-        return;
+        return false;
     }
 
-    let changed_files = match get_changed_files(&context.repo_path) {
+    let _lock = match lock_repo(backend, &context.repo_path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Failed to lock repository: {}", e);
+            return false;
+        }
+    };
+
+    let filter = match repo_path_filter(backend, &context.config, &context.repo_path) {
+        Ok(filter) => filter,
+        Err(e) => {
+            error!("Failed to build path filter: {}", e);
+            return false;
+        }
+    };
+
+    if let Some(autosave_ref) = &context.config.autosave_ref {
+        return autosave(backend, context, autosave_ref, &filter);
+    }
+
+    let mut changed_files = match backend.changed_files(&context.repo_path) {
         Ok(files) => files,
         Err(e) => {
             error!("Failed to get changed files: {}", e);
-            return;
+            return false;
         }
     };
+    changed_files.retain(|f| filter.is_match(f));
 
     if changed_files.is_empty() {
         debug!("No changed files");
-        return;
+        return false;
     }
 
-    if let Err(e) = commit_submodule_changes(&context) {
+    if let Err(e) = commit_submodule_changes(backend, context, &filter) {
         error!("Failed to commit submodule changes: {}", e);
     }
 
     // Re-check after submodule commits may have changed status
-    let changed_files = match get_changed_files(&context.repo_path) {
+    let mut changed_files = match backend.changed_files(&context.repo_path) {
         Ok(files) => files,
         Err(e) => {
             error!("Failed to get changed files: {}", e);
-            return;
+            return false;
         }
     };
+    changed_files.retain(|f| filter.is_match(f));
 
     if changed_files.is_empty() {
         debug!("No changed files after submodule commits");
-        return;
+        return false;
     }
 
-    let message = get_commit_message(&changed_files);
-    if let Err(e) = create_commit(&context.repo_path, &message) {
-        error!("Failed to create commit: {}", e);
-        return;
+    let hash = match create_commit(
+        backend,
+        &context.repo_path,
+        &changed_files,
+        &filter,
+        &context.config,
+    ) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return false,
+        Err(e) => {
+            error!("Failed to create commit: {}", e);
+            return false;
+        }
+    };
+    debug!("created commit {}", hash);
+
+    let needs_push = context.config.auto_push;
+    record_commit(&context.config.name, &context.repo_path, hash, needs_push);
+    needs_push
+}
+
+/// Pushes what `commit_changes` committed. Only the rebase onto the upstream
+/// branch holds the repository lock, so a slow remote doesn't hold up commits.
+/// Returns whether the push succeeded.
+pub fn push_changes(context: &EventContext) -> bool {
+    let backend = backend(context.config.git_backend);
+    let repo_path = &context.repo_path;
+
+    debug!("pushing {}", repo_path.display());
+    let result = match &context.config.autosave_ref {
+        Some(autosave_ref) => push_autosave_ref(backend, repo_path, autosave_ref),
+        None => {
+            push_submodules(backend, repo_path, &context.config);
+            push_commits(backend, repo_path, &context.config)
+        }
+    };
+    if let Err(e) = &result {
+        error!("Failed to push with error: {}", e);
     }
-    debug!("creating commit");
+    let pushed = result.is_ok();
+    record_state(&context.config.name, |state| {
+        state.last_push_error = result.err().map(|e| e.to_string());
+        if pushed {
+            state.needs_push.retain(|p| p != repo_path);
+        } else if !state.needs_push.contains(repo_path) {
+            state.needs_push.push(repo_path.clone());
+        }
+    });
+    pushed
+}
 
-    if context.config.auto_push {
-        debug!("pushing commit");
-        match push_commits(&context.repo_path) {
-            Ok(_) => (),
-            Err(e) => error!("Failed to push with error: {}", e),
-        };
-        debug!("pushed commit");
+/// The config's path filter for paths relative to the root of the repository, which
+/// may be a parent of the watched directory
+fn repo_path_filter(
+    backend: &dyn GitBackend,
+    config: &Config,
+    repo_path: &Path,
+) -> Result<PathFilter> {
+    let workdir = backend.workdir(repo_path)?.canonicalize()?;
+    let prefix = repo_path.canonicalize()?;
+    let prefix = prefix.strip_prefix(&workdir).unwrap_or(Path::new(""));
+    Ok(config.path_filter()?.within(prefix.to_path_buf()))
+}
+
+/// Records a snapshot of the working tree on `autosave_ref` instead of committing
+/// to the checked out branch. HEAD, the index and the working tree stay untouched.
+/// Returns whether a snapshot was made that should be pushed.
+fn autosave(
+    backend: &dyn GitBackend,
+    context: &EventContext,
+    autosave_ref: &str,
+    filter: &PathFilter,
+) -> bool {
+    let repo_path = &context.repo_path;
+    let snapshot = create_snapshot(backend, repo_path, autosave_ref, filter, &context.config);
+    let (refname, hash) = match snapshot {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            debug!("No changes since the last snapshot");
+            return false;
+        }
+        Err(e) => {
+            error!("Failed to create snapshot: {}", e);
+            return false;
+        }
+    };
+    debug!("saved snapshot {} to {}", hash, refname);
+
+    let needs_push = context.config.autosave_push;
+    record_commit(&context.config.name, repo_path, hash, needs_push);
+    needs_push
+}
+
+/// The full name of the autosave ref for the checked out branch
+fn autosave_refname(backend: &dyn GitBackend, repo_path: &Path, template: &str) -> Result<String> {
+    let branch = backend
+        .current_branch(repo_path)?
+        .unwrap_or_else(|| "detached".to_string());
+    let name = template.replace("{branch}", &branch);
+    // a plain name like `wip/{branch}` is a branch
+    if name.starts_with("refs/") {
+        Ok(name)
+    } else {
+        Ok(format!("refs/heads/{}", name))
     }
 }
 
-fn create_commit(repo_path: &Path, message: &str) -> Result<()> {
-    // Stage all changes
-    git(repo_path, &["add", "-A"])?;
-    git(repo_path, &["commit", "-m", message])?;
-    Ok(())
+/// Returns the ref and the hash of the new snapshot, or None if nothing changed
+/// since the previous one
+fn create_snapshot(
+    backend: &dyn GitBackend,
+    repo_path: &Path,
+    autosave_ref: &str,
+    filter: &PathFilter,
+    config: &Config,
+) -> Result<Option<(String, String)>> {
+    let refname = autosave_refname(backend, repo_path, autosave_ref)?;
+    let previous = backend.resolve(repo_path, &refname)?;
+    // the first snapshot of a branch builds on top of it
+    let parent = match &previous {
+        Some(previous) => Some(previous.clone()),
+        None => backend.resolve(repo_path, "HEAD")?,
+    };
+
+    let keep = |path: &str| filter.is_match(path);
+    let tree = backend.snapshot_tree(repo_path, parent.as_deref(), &keep)?;
+    let changed_files = backend.tree_changes(repo_path, parent.as_deref(), &tree)?;
+    if changed_files.is_empty() {
+        return Ok(None);
+    }
+
+    let message = match &config.commit_message_command {
+        Some(command) => {
+            let input = || match config.commit_message_command_input {
+                CommitMessageInput::Diff => backend.tree_diff(repo_path, parent.as_deref(), &tree),
+                CommitMessageInput::Json => Ok(serde_json::to_string(&changed_files)?),
+            };
+            run_commit_message_command(repo_path, input, config, command).unwrap_or_else(|e| {
+                error!(
+                    "Commit message command failed, using default message: {}",
+                    e
+                );
+                get_commit_message(&changed_files, config)
+            })
+        }
+        None => get_commit_message(&changed_files, config),
+    };
+
+    let message = with_trailer(&message, &config.name);
+    let hash = backend.commit_tree(repo_path, &tree, parent.as_deref(), &message)?;
+    backend.update_ref(repo_path, &refname, &hash, previous.as_deref())?;
+    Ok(Some((refname, hash)))
+}
+
+/// Pushes the checked out branch's autosave ref to the default remote
+fn push_autosave_ref(backend: &dyn GitBackend, repo_path: &Path, autosave_ref: &str) -> Result<()> {
+    let refname = autosave_refname(backend, repo_path, autosave_ref)?;
+    // the branch was switched since the snapshot and has none yet
+    if backend.resolve(repo_path, &refname)?.is_none() {
+        return Ok(());
+    }
+
+    backend.push_ref(repo_path, &default_remote(backend, repo_path)?, &refname)
 }
 
-fn get_commit_message(changed_files: &ChangedFiles) -> String {
-    let actions = ["Deleted", "Modified", "Added"];
+/// `origin`, or the only remote if there is no `origin`
+fn default_remote(backend: &dyn GitBackend, repo_path: &Path) -> Result<String> {
+    let remotes = backend.remotes(repo_path)?;
+    if remotes.iter().any(|r| r == "origin") {
+        return Ok("origin".to_string());
+    }
+    match remotes.as_slice() {
+        [remote] => Ok(remote.clone()),
+        [] => anyhow::bail!("The repository has no remote"),
+        _ => anyhow::bail!("The repository has several remotes and none is called origin"),
+    }
+}
+
+fn record_state(name: &str, f: impl FnOnce(&mut WatcherState)) {
+    if let Err(e) = WatcherState::update(name, f) {
+        error!("Failed to save watcher state: {}", e);
+    }
+}
+
+/// Also adds the repository to the push outbox if the commit is going to be pushed,
+/// so it still is after a restart
+fn record_commit(name: &str, repo_path: &Path, hash: String, needs_push: bool) {
+    record_state(name, |state| {
+        state.last_commit = Some(CommitRecord {
+            hash,
+            repo: Some(repo_path.to_path_buf()),
+            time: jiff::Zoned::now()
+                .strftime("%Y-%m-%dT%H:%M:%S%:z")
+                .to_string(),
+        });
+        if needs_push && !state.needs_push.iter().any(|p| p == repo_path) {
+            state.needs_push.push(repo_path.to_path_buf());
+        }
+    });
+}
+
+fn create_commit(
+    backend: &dyn GitBackend,
+    repo_path: &Path,
+    changed_files: &ChangedFiles,
+    filter: &PathFilter,
+    config: &Config,
+) -> Result<Option<String>> {
+    if filter.is_empty() {
+        // Stage all changes
+        backend.stage_all(repo_path)?;
+    } else {
+        // Only stage the files that passed the include/exclude patterns
+        let paths = changed_files
+            .paths()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        backend.stage_paths(repo_path, &paths)?;
+    }
+
+    // describe what is actually staged, which is also where renames become visible
+    let staged_files = backend.staged_files(repo_path)?;
+    if staged_files.is_empty() {
+        debug!("Nothing staged after adding changes");
+        return Ok(None);
+    }
+
+    let message = match &config.commit_message_command {
+        Some(command) => {
+            let input = || match config.commit_message_command_input {
+                CommitMessageInput::Diff => backend.staged_diff(repo_path),
+                CommitMessageInput::Json => Ok(serde_json::to_string(&staged_files)?),
+            };
+            run_commit_message_command(repo_path, input, config, command).unwrap_or_else(|e| {
+                error!(
+                    "Commit message command failed, using default message: {}",
+                    e
+                );
+                get_commit_message(&staged_files, config)
+            })
+        }
+        None => get_commit_message(&staged_files, config),
+    };
+
+    let message = with_trailer(&message, &config.name);
+    Ok(Some(backend.commit(repo_path, &message)?))
+}
+
+/// Runs the user's `commit_message_command` with `input`, either the diff or the
+/// changed files as JSON, on stdin and uses its stdout as the commit message.
+fn run_commit_message_command(
+    repo_path: &Path,
+    input: impl FnOnce() -> Result<String>,
+    config: &Config,
+    command: &str,
+) -> Result<String> {
+    let input = input()?;
+
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .current_dir(repo_path)
+        .env("WATCHERS_NAME", &config.name)
+        .env("WATCHERS_REPO", repo_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'", command))?;
+
+    // write and read on separate threads so a chatty command can't deadlock on a full pipe.
+    // The readers report back through channels, because a background process started by
+    // the command can keep the pipes open long after it exited.
+    let mut stdin = child.stdin.take().context("Failed to open stdin")?;
+    thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let mut stdout = child.stdout.take().context("Failed to open stdout")?;
+    let (stdout_tx, stdout_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout_tx.send(stdout.read_to_string(&mut output).map(|_| output));
+    });
+    let mut stderr = child.stderr.take().context("Failed to open stderr")?;
+    let (stderr_tx, stderr_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output);
+        let _ = stderr_tx.send(output);
+    });
+
+    let timeout = Duration::from_secs(config.commit_message_command_timeout_secs as u64);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("'{}' timed out after {:?}", command, timeout);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+    if !status.success() {
+        let stderr = stderr_rx.recv_timeout(remaining()).unwrap_or_default();
+        anyhow::bail!("'{}' exited with {}: {}", command, status, stderr.trim());
+    }
+
+    let output = stdout_rx.recv_timeout(remaining()).map_err(|_| {
+        anyhow::anyhow!("'{}' didn't close its output within {:?}", command, timeout)
+    })??;
+
+    let message = output.trim();
+    anyhow::ensure!(
+        !message.is_empty(),
+        "'{}' printed an empty message",
+        command
+    );
+    Ok(message.to_string())
+}
+
+fn with_trailer(message: &str, name: &str) -> String {
+    format!("{}\n\n{}: {}", message.trim_end(), AUTOSAVE_TRAILER, name)
+}
+
+fn get_commit_message(changed_files: &ChangedFiles, config: &Config) -> String {
+    let renamed = changed_files
+        .renamed
+        .iter()
+        .map(|r| format!("{} → {}", r.from, r.to))
+        .collect::<Vec<_>>();
+
+    let actions = ["Deleted", "Modified", "Added", "Renamed"];
     let types = [
         &changed_files.deleted,
         &changed_files.modified,
         &changed_files.added,
+        &renamed,
     ];
 
     let summary = types
@@ -180,63 +697,181 @@ fn get_commit_message(changed_files: &ChangedFiles) -> String {
         .collect::<Vec<String>>()
         .join("\n");
 
-    [summary, desc].join("\n\n")
+    let Some(template) = &config.commit_message_template else {
+        return [summary, desc].join("\n\n");
+    };
+
+    // the template is validated when the config is loaded
+    let template = match Template::parse(template) {
+        Ok(template) => template,
+        Err(e) => {
+            error!("Failed to parse commit message template: {}", e);
+            return [summary, desc].join("\n\n");
+        }
+    };
+
+    let vars = HashMap::from([
+        ("summary", summary),
+        ("files", desc),
+        ("deleted", changed_files.deleted.join("\n")),
+        ("modified", changed_files.modified.join("\n")),
+        ("added", changed_files.added.join("\n")),
+        ("renamed", renamed.join("\n")),
+        ("deleted_count", changed_files.deleted.len().to_string()),
+        ("modified_count", changed_files.modified.len().to_string()),
+        ("added_count", changed_files.added.len().to_string()),
+        ("renamed_count", changed_files.renamed.len().to_string()),
+        (
+            "total_count",
+            (types.iter().map(|ls| ls.len()).sum::<usize>()).to_string(),
+        ),
+        ("watcher", config.name.clone()),
+        (
+            "hostname",
+            gethostname::gethostname().to_string_lossy().to_string(),
+        ),
+        (
+            "timestamp",
+            jiff::Zoned::now()
+                .strftime("%Y-%m-%dT%H:%M:%S%:z")
+                .to_string(),
+        ),
+    ]);
+
+    template.render(&vars)
 }
 
-fn pull_and_rebase(repo_path: &Path) -> Result<bool> {
+/// Catches up with the upstream branch the way `strategy` says. Returns false if
+/// that isn't possible without resolving conflicts by hand.
+fn sync_with_upstream(
+    backend: &dyn GitBackend,
+    repo_path: &Path,
+    strategy: SyncStrategy,
+) -> Result<bool> {
+    if strategy == SyncStrategy::SkipPull {
+        return Ok(true);
+    }
+
     // Fetch from remote
-    if let Err(e) = git(repo_path, &["fetch"]) {
-        debug!("Fetch failed: {}, skipping rebase", e);
+    if let Err(e) = backend.fetch(repo_path) {
+        debug!("Fetch failed: {}, skipping sync", e);
         return Ok(true);
     }
 
     // Check if there's an upstream branch
-    let upstream = match git(repo_path, &["rev-parse", "--abbrev-ref", "@{upstream}"]) {
-        Ok(u) => u,
-        Err(_) => return Ok(true), // No upstream tracking branch
+    let Some(upstream) = backend.upstream(repo_path)? else {
+        return Ok(true); // No upstream tracking branch
     };
 
     debug!("Upstream: {}", upstream);
 
-    // Attempt rebase, abort on conflicts
-    match git(repo_path, &["rebase", &upstream]) {
-        Ok(_) => {
-            debug!("Rebase completed successfully");
-            Ok(true)
-        }
+    // nothing to catch up with, so local changes can't get in the way
+    if let Some((_, 0)) = backend.ahead_behind(repo_path)? {
+        return Ok(true);
+    }
+
+    // this rewrites HEAD and the working tree, so it mustn't run during a commit
+    let _lock = lock_repo(backend, repo_path)?;
+    // the backend aborts on conflicts
+    let synced = match strategy {
+        SyncStrategy::Merge => backend.merge(repo_path, &upstream)?,
+        SyncStrategy::FfOnly => backend.fast_forward(repo_path, &upstream)?,
+        _ => backend.rebase(repo_path, &upstream)?,
+    };
+    if synced {
+        debug!("Caught up with {}", upstream);
+    }
+    Ok(synced)
+}
+
+fn push_commits(backend: &dyn GitBackend, repo_path: &Path, config: &Config) -> Result<()> {
+    let synced = match sync_with_upstream(backend, repo_path, config.sync_strategy) {
+        Ok(synced) => synced,
         Err(e) => {
-            debug!("Rebase failed: {}, aborting", e);
-            let _ = git(repo_path, &["rebase", "--abort"]);
-            Ok(false)
+            debug!("Sync with upstream failed: {}, will attempt push anyway", e);
+            true
         }
+    };
+
+    if synced {
+        backend.push(repo_path)?;
+        forget_conflict_branch(&config.name, repo_path);
+        return Ok(());
     }
-}
 
-fn push_commits(repo_path: &Path) -> Result<()> {
-    match pull_and_rebase(repo_path) {
-        Ok(true) => debug!("Pull rebase succeeded or not needed"),
-        Ok(false) => debug!("Skipping rebase due to conflicts, will attempt push anyway"),
-        Err(e) => debug!("Pull rebase failed: {}, will attempt push anyway", e),
+    match config.sync_strategy {
+        SyncStrategy::AutosaveConflictsToBranch => {
+            push_conflict_branch(backend, repo_path, &config.name)
+        }
+        SyncStrategy::Merge => {
+            anyhow::bail!("Merging the upstream branch hit conflicts, resolve them by hand")
+        }
+        SyncStrategy::FfOnly => anyhow::bail!(
+            "The branch has diverged from its upstream and sync_strategy is ff-only, \
+             resolve this by hand"
+        ),
+        _ => anyhow::bail!("Rebasing onto the upstream branch hit conflicts, resolve them by hand"),
     }
+}
+
+/// Pushes HEAD to a `watchers/conflict-<host>-<time>` branch on the default remote
+/// when it conflicts with its upstream, so the commits aren't stuck on this machine.
+/// Later pushes go to the same branch until the conflict is resolved.
+fn push_conflict_branch(backend: &dyn GitBackend, repo_path: &Path, name: &str) -> Result<()> {
+    let state = WatcherState::load(name).unwrap_or_default();
+    let branch = match state.conflict_branches.get(repo_path) {
+        Some(branch) => branch.clone(),
+        None => format!(
+            "watchers/conflict-{}-{}",
+            gethostname::gethostname().to_string_lossy(),
+            jiff::Zoned::now().strftime("%Y%m%d-%H%M%S")
+        ),
+    };
 
-    git(repo_path, &["push"])?;
+    let refname = format!("refs/heads/{}", branch);
+    let head = backend
+        .resolve(repo_path, "HEAD")?
+        .context("HEAD doesn't point at a commit")?;
+    let previous = backend.resolve(repo_path, &refname)?;
+    backend.update_ref(repo_path, &refname, &head, previous.as_deref())?;
+    backend.push_ref(repo_path, &default_remote(backend, repo_path)?, &refname)?;
+
+    warn!(
+        "{} conflicts with its upstream branch, pushed it to {} instead",
+        repo_path.display(),
+        branch
+    );
+    record_state(name, |state| {
+        state
+            .conflict_branches
+            .insert(repo_path.to_path_buf(), branch);
+    });
     Ok(())
 }
 
-fn commit_submodule_changes(context: &EventContext) -> Result<()> {
-    let output = git(
-        &context.repo_path,
-        &["submodule", "foreach", "--quiet", "echo $sm_path"],
-    )?;
-
-    if output.is_empty() {
-        return Ok(());
+/// Stops pushing to the repository's conflict branch once it is back in sync
+fn forget_conflict_branch(name: &str, repo_path: &Path) {
+    let state = WatcherState::load(name).unwrap_or_default();
+    if state.conflict_branches.contains_key(repo_path) {
+        info!(
+            "{} is back in sync with its upstream branch",
+            repo_path.display()
+        );
+        record_state(name, |state| {
+            state.conflict_branches.remove(repo_path);
+        });
     }
+}
 
-    for submodule_rel_path in output.lines() {
-        let submodule_path = context.repo_path.join(submodule_rel_path);
+fn commit_submodule_changes(
+    backend: &dyn GitBackend,
+    context: &EventContext,
+    filter: &PathFilter,
+) -> Result<()> {
+    for submodule_rel_path in backend.submodule_paths(&context.repo_path)? {
+        let submodule_path = context.repo_path.join(&submodule_rel_path);
 
-        let changed_files = match get_changed_files(&submodule_path) {
+        let mut changed_files = match backend.changed_files(&submodule_path) {
             Ok(files) => files,
             Err(e) => {
                 error!(
@@ -246,13 +881,20 @@ fn commit_submodule_changes(context: &EventContext) -> Result<()> {
                 continue;
             }
         };
+        // patterns are relative to the watched repo, not the submodule
+        changed_files.retain(|f| filter.is_match(Path::new(&submodule_rel_path).join(f)));
 
         if changed_files.is_empty() {
             continue;
         }
 
-        let message = get_commit_message(&changed_files);
-        if let Err(e) = create_commit(&submodule_path, &message) {
+        if let Err(e) = create_commit(
+            backend,
+            &submodule_path,
+            &changed_files,
+            filter,
+            &context.config,
+        ) {
             error!(
                 "Failed to commit submodule changes at {:?}: {}",
                 submodule_path, e
@@ -261,42 +903,66 @@ fn commit_submodule_changes(context: &EventContext) -> Result<()> {
         }
 
         debug!("Created commit in submodule: {:?}", submodule_path);
-
-        if context.config.auto_push {
-            if let Err(e) = push_commits(&submodule_path) {
-                error!("Failed to push submodule at {:?}: {}", submodule_path, e);
-            } else {
-                debug!("Pushed submodule: {:?}", submodule_path);
-            }
-        }
     }
 
     Ok(())
 }
 
-pub fn is_git_ignored<P: AsRef<Path>>(paths: &[P]) -> Result<bool> {
-    if paths.is_empty() {
-        return Ok(false);
-    }
-
-    // Find the repo root from the first path
-    let first_parent = paths[0]
-        .as_ref()
-        .parent()
-        .context("Path has no parent")?;
+/// Pushes submodules with commits their upstream doesn't have yet
+fn push_submodules(backend: &dyn GitBackend, repo_path: &Path, config: &Config) {
+    let submodule_paths = match backend.submodule_paths(repo_path) {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!("Failed to list submodules of {:?}: {}", repo_path, e);
+            return;
+        }
+    };
 
-    for p in paths {
-        let output = Command::new("git")
-            .args(["-C", &first_parent.to_string_lossy()])
-            .args(["check-ignore", "-q"])
-            .arg(p.as_ref())
-            .output()
-            .context("Failed to run git check-ignore")?;
+    for submodule_rel_path in submodule_paths {
+        let submodule_path = repo_path.join(&submodule_rel_path);
+        if !matches!(backend.ahead_behind(&submodule_path), Ok(Some((ahead, _))) if ahead > 0) {
+            continue;
+        }
 
-        if output.status.success() {
-            return Ok(true);
+        if let Err(e) = push_commits(backend, &submodule_path, config) {
+            error!("Failed to push submodule at {:?}: {}", submodule_path, e);
+        } else {
+            debug!("Pushed submodule: {:?}", submodule_path);
         }
     }
+}
+
+/// Summary of the repository state for `watchers status`
+#[derive(Debug, Serialize)]
+pub struct RepoStatus {
+    pub path: PathBuf,
+    /// None if the repository couldn't be read
+    pub pending_changes: Option<usize>,
+    pub ahead: Option<usize>,
+    pub behind: Option<usize>,
+}
+
+pub fn get_repo_status(config: &Config, repo_path: &Path) -> Result<RepoStatus> {
+    let backend = backend(config.git_backend);
+    let filter = repo_path_filter(backend, config, repo_path)?;
 
-    Ok(false)
+    let mut changed_files = backend.changed_files(repo_path)?;
+    changed_files.retain(|f| filter.is_match(f));
+    let (ahead, behind) = backend.ahead_behind(repo_path)?.unzip();
+
+    Ok(RepoStatus {
+        path: repo_path.to_path_buf(),
+        pending_changes: Some(changed_files.paths().count()),
+        ahead,
+        behind,
+    })
+}
+
+/// Names of the repository's remotes, or None if `repo_path` isn't a git repository yet.
+pub fn get_repo_remotes(config: &Config, repo_path: &Path) -> Result<Option<Vec<String>>> {
+    let backend = backend(config.git_backend);
+    if !backend.is_repo(repo_path) {
+        return Ok(None);
+    }
+    Ok(Some(backend.remotes(repo_path)?))
 }
@@ -0,0 +1,385 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{ChangedFiles, GitBackend, RenamedFile, git, git_raw, git_raw_env};
+
+/// Records a rename as `from -> to`, a copy leaves `from` untouched so it is just an added file
+fn push_rename_or_copy(files: &mut ChangedFiles, score: &str, from: &str, to: &str) {
+    if score.starts_with('C') {
+        files.added.push(to.to_string());
+    } else {
+        files.renamed.push(RenamedFile {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+}
+
+/// Parses `--name-status -z` output of `git diff` and friends
+fn parse_name_status(output: &str) -> ChangedFiles {
+    let mut files = ChangedFiles::default();
+    let mut fields = output.split('\0').filter(|e| !e.is_empty());
+
+    while let Some(status) = fields.next() {
+        let Some(path) = fields.next() else {
+            break;
+        };
+        match &status[..1] {
+            "D" => files.deleted.push(path.to_string()),
+            "A" => files.added.push(path.to_string()),
+            "R" | "C" => {
+                let Some(to) = fields.next() else {
+                    break;
+                };
+                push_rename_or_copy(&mut files, status, path, to);
+            }
+            _ => files.modified.push(path.to_string()),
+        }
+    }
+
+    files
+}
+
+/// `base`, or the empty tree for an unborn branch
+fn base_or_empty_tree(repo_path: &Path, base: Option<&str>) -> Result<String> {
+    match base {
+        Some(base) => Ok(base.to_string()),
+        None => git(repo_path, &["hash-object", "-t", "tree", "/dev/null"]),
+    }
+}
+
+/// Backend that shells out to the `git` executable.
+pub struct CliBackend;
+
+impl GitBackend for CliBackend {
+    fn is_repo(&self, repo_path: &Path) -> bool {
+        git(repo_path, &["rev-parse", "--git-dir"]).is_ok()
+    }
+
+    fn git_dir(&self, repo_path: &Path) -> Result<PathBuf> {
+        Ok(git(repo_path, &["rev-parse", "--absolute-git-dir"])?.into())
+    }
+
+    fn workdir(&self, repo_path: &Path) -> Result<PathBuf> {
+        Ok(git(repo_path, &["rev-parse", "--show-toplevel"])?.into())
+    }
+
+    fn init(&self, repo_path: &Path) -> Result<()> {
+        git(repo_path, &["init"])?;
+        Ok(())
+    }
+
+    fn clone(&self, url: &str, repo_path: &Path) -> Result<()> {
+        let parent = repo_path
+            .parent()
+            .context("Can't clone into the root directory")?;
+        fs::create_dir_all(parent)?;
+        git(parent, &["clone", "--", url, &repo_path.to_string_lossy()])?;
+        Ok(())
+    }
+
+    fn changed_files(&self, repo_path: &Path) -> Result<ChangedFiles> {
+        // porcelain v2 with -z gives stable output with unquoted paths and rename sources,
+        // and files in new directories are listed one by one so the filter sees them
+        let output = git_raw(
+            repo_path,
+            &["status", "--porcelain=v2", "-z", "--untracked-files=all"],
+        )?;
+        let mut files = ChangedFiles::default();
+        let mut entries = output.split('\0').filter(|e| !e.is_empty());
+
+        while let Some(entry) = entries.next() {
+            let (kind, rest) = (&entry[..1], entry.get(2..).unwrap_or_default());
+            match kind {
+                // Untracked (new) file
+                "?" => files.added.push(rest.to_string()),
+                // Ordinary change: XY sub mH mI mW hH hI path
+                "1" => {
+                    let fields = rest.splitn(8, ' ').collect::<Vec<_>>();
+                    let (Some(xy), Some(path)) = (fields.first(), fields.get(7)) else {
+                        continue;
+                    };
+                    let path = path.to_string();
+                    if xy.contains('D') {
+                        files.deleted.push(path);
+                    } else if xy.starts_with('A') {
+                        files.added.push(path);
+                    } else {
+                        files.modified.push(path);
+                    }
+                }
+                // Rename or copy: XY sub mH mI mW hH hI score path, then the source path
+                "2" => {
+                    let fields = rest.splitn(9, ' ').collect::<Vec<_>>();
+                    let (Some(score), Some(path)) = (fields.get(7), fields.get(8)) else {
+                        continue;
+                    };
+                    let Some(from) = entries.next() else {
+                        continue;
+                    };
+                    push_rename_or_copy(&mut files, score, from, path);
+                }
+                // Unmerged: XY sub m1 m2 m3 mW h1 h2 h3 path
+                "u" => {
+                    if let Some(path) = rest.splitn(10, ' ').nth(9) {
+                        files.modified.push(path.to_string());
+                    }
+                }
+                // Ignored files and headers
+                _ => (),
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn staged_files(&self, repo_path: &Path) -> Result<ChangedFiles> {
+        let output = git_raw(
+            repo_path,
+            &["diff", "--cached", "--name-status", "-z", "-M", "-C"],
+        )?;
+        Ok(parse_name_status(&output))
+    }
+
+    fn stage_all(&self, repo_path: &Path) -> Result<()> {
+        git(repo_path, &["add", "-A"])?;
+        Ok(())
+    }
+
+    fn stage_paths(&self, repo_path: &Path, paths: &[&str]) -> Result<()> {
+        // the paths are relative to the top level, not the watched directory
+        let repo_path = &self.workdir(repo_path)?;
+        // `git add` rejects paths that are neither on disk nor in the index, such as
+        // the source of an already staged rename, so only keep missing paths that are tracked
+        let (mut existing, missing): (Vec<&str>, Vec<&str>) = paths
+            .iter()
+            .partition(|p| repo_path.join(p).symlink_metadata().is_ok());
+        if !missing.is_empty() {
+            let mut args = vec!["--literal-pathspecs", "ls-files", "-z", "--"];
+            args.extend(&missing);
+            let tracked = git_raw(repo_path, &args)?;
+            existing.extend(
+                missing
+                    .iter()
+                    .filter(|p| tracked.split('\0').any(|t| t == **p)),
+            );
+        }
+
+        if existing.is_empty() {
+            return Ok(());
+        }
+
+        // file names like `*.md` would otherwise match other files too
+        let mut args = vec!["--literal-pathspecs", "add", "-A", "--"];
+        args.extend(existing);
+        git(repo_path, &args)?;
+        Ok(())
+    }
+
+    fn staged_diff(&self, repo_path: &Path) -> Result<String> {
+        git(repo_path, &["diff", "--cached"])
+    }
+
+    fn commit(&self, repo_path: &Path, message: &str) -> Result<String> {
+        git(repo_path, &["commit", "-m", message])?;
+        git(repo_path, &["rev-parse", "HEAD"])
+    }
+
+    fn fetch(&self, repo_path: &Path) -> Result<()> {
+        git(repo_path, &["fetch"])?;
+        Ok(())
+    }
+
+    fn upstream(&self, repo_path: &Path) -> Result<Option<String>> {
+        // fails when there is no upstream tracking branch
+        Ok(git(repo_path, &["rev-parse", "--abbrev-ref", "@{upstream}"]).ok())
+    }
+
+    fn rebase(&self, repo_path: &Path, upstream: &str) -> Result<bool> {
+        // local changes, e.g. to excluded files, are set aside instead of stopping the rebase
+        let Err(e) = git(repo_path, &["rebase", "--autostash", upstream]) else {
+            return Ok(true);
+        };
+
+        // a rebase that stopped part way hit conflicts, anything else failed before it started
+        let git_dir = self.git_dir(repo_path)?;
+        if !git_dir.join("rebase-merge").exists() && !git_dir.join("rebase-apply").exists() {
+            return Err(e);
+        }
+        debug!("Rebase hit conflicts: {}, aborting", e);
+        git(repo_path, &["rebase", "--abort"])?;
+        Ok(false)
+    }
+
+    fn merge(&self, repo_path: &Path, upstream: &str) -> Result<bool> {
+        let Err(e) = git(repo_path, &["merge", "--no-edit", "--autostash", upstream]) else {
+            return Ok(true);
+        };
+
+        if !self.git_dir(repo_path)?.join("MERGE_HEAD").exists() {
+            return Err(e);
+        }
+        debug!("Merge hit conflicts: {}, aborting", e);
+        git(repo_path, &["merge", "--abort"])?;
+        Ok(false)
+    }
+
+    fn fast_forward(&self, repo_path: &Path, upstream: &str) -> Result<bool> {
+        // fails when HEAD isn't an ancestor of the upstream branch
+        if git(
+            repo_path,
+            &["merge-base", "--is-ancestor", "HEAD", upstream],
+        )
+        .is_err()
+        {
+            return Ok(false);
+        }
+        git(repo_path, &["merge", "--ff-only", "--autostash", upstream])?;
+        Ok(true)
+    }
+
+    fn push(&self, repo_path: &Path) -> Result<()> {
+        git(repo_path, &["push"])?;
+        Ok(())
+    }
+
+    fn ahead_behind(&self, repo_path: &Path) -> Result<Option<(usize, usize)>> {
+        if self.upstream(repo_path)?.is_none() {
+            return Ok(None);
+        }
+
+        let output = git(
+            repo_path,
+            &["rev-list", "--left-right", "--count", "HEAD...@{upstream}"],
+        )?;
+        let mut counts = output.split_whitespace().map(str::parse::<usize>);
+        match (counts.next(), counts.next()) {
+            (Some(Ok(ahead)), Some(Ok(behind))) => Ok(Some((ahead, behind))),
+            _ => anyhow::bail!("Unexpected rev-list output: {}", output),
+        }
+    }
+
+    fn remotes(&self, repo_path: &Path) -> Result<Vec<String>> {
+        let output = git(repo_path, &["remote"])?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+
+    fn submodule_paths(&self, repo_path: &Path) -> Result<Vec<String>> {
+        let output = git(
+            repo_path,
+            &["submodule", "foreach", "--quiet", "echo $sm_path"],
+        )?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+
+    fn current_branch(&self, repo_path: &Path) -> Result<Option<String>> {
+        // fails when HEAD is detached, works on an unborn branch
+        Ok(git(repo_path, &["symbolic-ref", "--quiet", "--short", "HEAD"]).ok())
+    }
+
+    fn resolve(&self, repo_path: &Path, rev: &str) -> Result<Option<String>> {
+        let rev = format!("{}^{{commit}}", rev);
+        Ok(git(repo_path, &["rev-parse", "--verify", "--quiet", &rev]).ok())
+    }
+
+    fn snapshot_tree(
+        &self,
+        repo_path: &Path,
+        base: Option<&str>,
+        keep: &dyn Fn(&str) -> bool,
+    ) -> Result<String> {
+        // the paths git prints are relative to the top level, not the watched directory
+        let repo_path = &self.workdir(repo_path)?;
+        let dir = tempfile::tempdir()?;
+        let index = dir.path().join("index");
+        let envs = [("GIT_INDEX_FILE", index.as_path())];
+        let git_index = |args: &[&str]| git_raw_env(repo_path, &envs, args);
+
+        match base {
+            Some(base) => git_index(&["read-tree", base])?,
+            None => git_index(&["read-tree", "--empty"])?,
+        };
+        git_index(&["add", "-A"])?;
+
+        // put back what the filter rejected, without renames so each path is judged on its own
+        let mut args = vec!["diff", "--cached", "--name-only", "--no-renames", "-z"];
+        args.extend(base);
+        let changed = git_index(&args)?;
+        let rejected = changed
+            .split('\0')
+            .filter(|p| !p.is_empty() && !keep(p))
+            .collect::<Vec<_>>();
+        if !rejected.is_empty() {
+            let mut args = match base {
+                Some(base) => vec!["--literal-pathspecs", "reset", "-q", base, "--"],
+                None => vec!["--literal-pathspecs", "rm", "--cached", "-q", "--"],
+            };
+            args.extend(rejected);
+            git_index(&args)?;
+        }
+
+        Ok(git_index(&["write-tree"])?.trim().to_string())
+    }
+
+    fn tree_changes(
+        &self,
+        repo_path: &Path,
+        base: Option<&str>,
+        tree: &str,
+    ) -> Result<ChangedFiles> {
+        let base = base_or_empty_tree(repo_path, base)?;
+        let output = git_raw(
+            repo_path,
+            &["diff-tree", "-r", "--name-status", "-z", "-M", "-C"]
+                .into_iter()
+                .chain([base.as_str(), tree])
+                .collect::<Vec<_>>(),
+        )?;
+        Ok(parse_name_status(&output))
+    }
+
+    fn tree_diff(&self, repo_path: &Path, base: Option<&str>, tree: &str) -> Result<String> {
+        let base = base_or_empty_tree(repo_path, base)?;
+        git(repo_path, &["diff-tree", "-r", "-p", &base, tree])
+    }
+
+    fn commit_tree(
+        &self,
+        repo_path: &Path,
+        tree: &str,
+        parent: Option<&str>,
+        message: &str,
+    ) -> Result<String> {
+        let mut args = vec!["commit-tree", tree, "-m", message];
+        if let Some(parent) = parent {
+            args.extend(["-p", parent]);
+        }
+        git(repo_path, &args)
+    }
+
+    fn update_ref(
+        &self,
+        repo_path: &Path,
+        refname: &str,
+        new: &str,
+        old: Option<&str>,
+    ) -> Result<()> {
+        // an empty old value makes sure the ref doesn't exist yet
+        let old = old.unwrap_or("");
+        git(
+            repo_path,
+            &["update-ref", "-m", "watchers: snapshot", refname, new, old],
+        )?;
+        Ok(())
+    }
+
+    fn push_ref(&self, repo_path: &Path, remote: &str, refname: &str) -> Result<()> {
+        let refspec = format!("{}:{}", refname, refname);
+        git(repo_path, &["push", remote, &refspec])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use ignore::{
+    Match, WalkBuilder,
+    gitignore::{Gitignore, GitignoreBuilder, gitconfig_excludes_path},
+};
+use log::{debug, error};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::SystemTime,
+};
+
+/// In-memory matcher for `.gitignore` files, `.git/info/exclude` and the global
+/// excludes file, so checking a path doesn't need a `git check-ignore` process.
+///
+/// The rules are rebuilt lazily once any of those files change.
+pub struct IgnoreMatcher {
+    watch_dir: PathBuf,
+    canonical_watch_dir: PathBuf,
+    stale: AtomicBool,
+    rules: RwLock<Rules>,
+}
+
+#[derive(Default)]
+struct Rules {
+    // sorted from the repo root down, so the deepest file can be checked first
+    gitignores: Vec<Gitignore>,
+    exclude: Option<Gitignore>,
+    global: Option<Gitignore>,
+    exclude_path: Option<PathBuf>,
+    global_path: Option<PathBuf>,
+    // modification times of the files outside the watched tree when last loaded
+    mtimes: Vec<Option<SystemTime>>,
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn find_repo_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|d| d.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+fn load_gitignore(path: &Path) -> Option<Gitignore> {
+    let (gitignore, err) = Gitignore::new(path);
+    if let Some(e) = err {
+        error!("Failed to parse {}: {}", path.display(), e);
+    }
+    (!gitignore.is_empty()).then_some(gitignore)
+}
+
+fn load_rooted(root: &Path, path: &Path) -> Option<Gitignore> {
+    if !path.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(root);
+    if let Some(e) = builder.add(path) {
+        error!("Failed to parse {}: {}", path.display(), e);
+    }
+    builder.build().ok().filter(|g| !g.is_empty())
+}
+
+impl Rules {
+    fn load(watch_dir: &Path) -> Rules {
+        let repo_root = find_repo_root(watch_dir).unwrap_or_else(|| watch_dir.to_path_buf());
+
+        // .gitignore files between the repo root and the watched directory
+        let mut paths = watch_dir
+            .ancestors()
+            .skip(1)
+            .take_while(|d| d.starts_with(&repo_root))
+            .map(|d| d.join(".gitignore"))
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>();
+        paths.reverse();
+
+        // and every .gitignore inside it, without descending into ignored directories
+        let walker = WalkBuilder::new(watch_dir)
+            .hidden(false)
+            .filter_entry(|e| e.file_name() != ".git")
+            .build();
+        paths.extend(
+            walker
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name() == ".gitignore")
+                .map(|e| e.into_path()),
+        );
+
+        let exclude_path = Some(repo_root.join(".git/info/exclude"));
+        let global_path = gitconfig_excludes_path();
+        let mtimes = [&exclude_path, &global_path]
+            .iter()
+            .map(|p| p.as_deref().and_then(mtime))
+            .collect();
+
+        let mut gitignores = paths
+            .iter()
+            .filter_map(|p| load_gitignore(p))
+            .collect::<Vec<_>>();
+        gitignores.sort_by_key(|g| g.path().components().count());
+
+        Rules {
+            gitignores,
+            exclude: exclude_path
+                .as_deref()
+                .and_then(|p| load_rooted(&repo_root, p)),
+            global: global_path
+                .as_deref()
+                .and_then(|p| load_rooted(&repo_root, p)),
+            exclude_path,
+            global_path,
+            mtimes,
+        }
+    }
+
+    fn is_outdated(&self) -> bool {
+        [&self.exclude_path, &self.global_path]
+            .iter()
+            .map(|p| p.as_deref().and_then(mtime))
+            .ne(self.mtimes.iter().copied())
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        // git gives precedence to the deepest .gitignore, then info/exclude, then the global file
+        let matchers = self
+            .gitignores
+            .iter()
+            .rev()
+            .chain(self.exclude.iter())
+            .chain(self.global.iter());
+
+        for gitignore in matchers {
+            if !path.starts_with(gitignore.path()) {
+                continue;
+            }
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+        false
+    }
+}
+
+impl IgnoreMatcher {
+    pub fn new(watch_dir: impl Into<PathBuf>) -> Self {
+        let watch_dir = watch_dir.into();
+        let canonical_watch_dir =
+            fs::canonicalize(&watch_dir).unwrap_or_else(|_| watch_dir.clone());
+        let rules = Rules::load(&canonical_watch_dir);
+
+        Self {
+            watch_dir,
+            canonical_watch_dir,
+            stale: AtomicBool::new(false),
+            rules: RwLock::new(rules),
+        }
+    }
+
+    /// Marks the rules for reloading if any of `paths` is an ignore file.
+    pub fn on_paths_changed(&self, paths: &[impl AsRef<Path>]) {
+        let rules = self.rules.read().unwrap();
+        let changed = paths.iter().any(|p| {
+            let p = p.as_ref();
+            p.file_name().is_some_and(|n| n == ".gitignore")
+                || p.ends_with(".git/info/exclude")
+                || rules.global_path.as_deref() == Some(p)
+        });
+
+        if changed {
+            self.stale.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn reload_if_needed(&self) {
+        let outdated = self.rules.read().unwrap().is_outdated();
+        if self.stale.swap(false, Ordering::Relaxed) || outdated {
+            debug!("reloading ignore rules for {:?}", self.watch_dir);
+            *self.rules.write().unwrap() = Rules::load(&self.canonical_watch_dir);
+        }
+    }
+
+    /// Returns true if any of `paths` is ignored by git. Paths outside of the
+    /// watched directory are never ignored.
+    pub fn is_any_ignored(&self, paths: &[impl AsRef<Path>]) -> bool {
+        self.reload_if_needed();
+        let rules = self.rules.read().unwrap();
+
+        paths.iter().any(|p| {
+            let p = p.as_ref();
+            let Ok(rel_path) = p.strip_prefix(&self.watch_dir) else {
+                return false;
+            };
+            rules.is_ignored(&self.canonical_watch_dir.join(rel_path), p.is_dir())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A repository with `files`, where a trailing slash makes a directory
+    fn repo_with(files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let status = Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        for (path, content) in files {
+            let path = dir.path().join(path);
+            if path.to_string_lossy().ends_with('/') {
+                fs::create_dir_all(path).unwrap();
+            } else {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+        }
+        dir
+    }
+
+    fn check_ignore(dir: &Path, path: &str) -> bool {
+        let status = Command::new("git")
+            .args(["check-ignore", "-q", "--", path])
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(matches!(status.code(), Some(0 | 1)), "{}", path);
+        status.success()
+    }
+
+    /// Checks every path in `paths` against git's own answer
+    fn assert_matches_git(watch_dir: &Path, paths: &[&str]) {
+        let matcher = IgnoreMatcher::new(watch_dir);
+        for path in paths {
+            let ignored = matcher.is_any_ignored(&[watch_dir.join(path)]);
+            assert_eq!(ignored, check_ignore(watch_dir, path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn precedence_matches_git() {
+        let repo = repo_with(&[
+            (".gitignore", "*.log\n!keep.log\n!keep.bak\n"),
+            (".git/info/exclude", "*.bak\nsecret\n"),
+            ("sub/.gitignore", "!important.log\nkeep.log\nlocal\n"),
+            ("a.log", "x"),
+            ("keep.log", "x"),
+            ("sub/a.log", "x"),
+            ("sub/important.log", "x"),
+            ("sub/keep.log", "x"),
+            ("sub/local", "x"),
+            ("local", "x"),
+            ("other.bak", "x"),
+            ("keep.bak", "x"),
+            ("secret", "x"),
+            ("sub/secret", "x"),
+            ("readme.md", "x"),
+        ]);
+        assert_matches_git(
+            repo.path(),
+            &[
+                "a.log",
+                "keep.log",
+                "sub/a.log",
+                "sub/important.log",
+                "sub/keep.log",
+                "sub/local",
+                "local",
+                "other.bak",
+                "keep.bak",
+                "secret",
+                "sub/secret",
+                "readme.md",
+            ],
+        );
+    }
+
+    #[test]
+    fn anchoring_matches_git() {
+        let repo = repo_with(&[
+            (
+                ".gitignore",
+                "/build\ndocs/*.tmp\ncache/\n**/gen/*.rs\nnotes/**/draft\n",
+            ),
+            ("build/out", "x"),
+            ("sub/build/out", "x"),
+            ("docs/a.tmp", "x"),
+            ("docs/deep/a.tmp", "x"),
+            ("sub/docs/a.tmp", "x"),
+            ("cache/", ""),
+            ("sub/cache/", ""),
+            ("file/cache", "x"),
+            ("a/b/gen/x.rs", "x"),
+            ("gen/x.rs", "x"),
+            ("notes/draft", "x"),
+            ("notes/x/y/draft", "x"),
+        ]);
+        assert_matches_git(
+            repo.path(),
+            &[
+                "build",
+                "build/out",
+                "sub/build/out",
+                "docs/a.tmp",
+                "docs/deep/a.tmp",
+                "sub/docs/a.tmp",
+                "cache",
+                "sub/cache",
+                "file/cache",
+                "a/b/gen/x.rs",
+                "gen/x.rs",
+                "notes/draft",
+                "notes/x/y/draft",
+            ],
+        );
+    }
+
+    #[test]
+    fn rules_above_the_watched_directory_apply() {
+        let repo = repo_with(&[
+            (".gitignore", "*.log\n/top\nsub/anchored\n"),
+            ("sub/.gitignore", "!keep.log\n"),
+            ("sub/a.log", "x"),
+            ("sub/keep.log", "x"),
+            ("sub/top", "x"),
+            ("sub/anchored", "x"),
+        ]);
+        assert_matches_git(
+            &repo.path().join("sub"),
+            &["a.log", "keep.log", "top", "anchored"],
+        );
+    }
+}
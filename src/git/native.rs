@@ -0,0 +1,599 @@
+use anyhow::{Context, Result};
+use git2::{
+    BranchType, Cred, CredentialType, Delta, DiffFindOptions, DiffFormat, FetchOptions, Index,
+    IndexAddOption, IndexMatchedPath, MergeAnalysis, Oid, PushOptions, RemoteCallbacks, Repository,
+    StashApplyOptions, Status, StatusOptions,
+    build::{CheckoutBuilder, RepoBuilder},
+};
+use log::debug;
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+};
+
+use super::{ChangedFiles, GitBackend, RenamedFile};
+
+/// Backend that talks to the repository in-process through libgit2.
+pub struct NativeBackend;
+
+fn open(repo_path: &Path) -> Result<Repository> {
+    Repository::discover(repo_path)
+        .with_context(|| format!("Failed to open repository at {}", repo_path.display()))
+}
+
+fn workdir(repo: &Repository) -> Result<PathBuf> {
+    repo.workdir()
+        .map(Path::to_path_buf)
+        .context("Repository has no working directory")
+}
+
+/// Credentials in the same order the git CLI would try them: ssh-agent, then
+/// the configured credential helper, then the default credentials.
+fn remote_callbacks<'a>(repo: &'a Repository) -> RemoteCallbacks<'a> {
+    credential_callbacks(move || repo.config())
+}
+
+/// Like `remote_callbacks` before there is a repository, e.g. when cloning
+fn default_remote_callbacks() -> RemoteCallbacks<'static> {
+    credential_callbacks(git2::Config::open_default)
+}
+
+fn credential_callbacks<'a>(
+    config: impl Fn() -> Result<git2::Config, git2::Error> + 'a,
+) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    // libgit2 calls back again after every rejected credential, so each kind is
+    // only offered once before giving up
+    let mut tried = CredentialType::empty();
+    callbacks.credentials(move |url, username, allowed| {
+        let untried = |kind| allowed.contains(kind) && !tried.contains(kind);
+        if untried(CredentialType::SSH_KEY)
+            && let Some(username) = username
+        {
+            tried.insert(CredentialType::SSH_KEY);
+            return Cred::ssh_key_from_agent(username);
+        }
+        if untried(CredentialType::USER_PASS_PLAINTEXT) {
+            tried.insert(CredentialType::USER_PASS_PLAINTEXT);
+            return Cred::credential_helper(&config()?, url, username);
+        }
+        if !tried.contains(CredentialType::DEFAULT) {
+            tried.insert(CredentialType::DEFAULT);
+            return Cred::default();
+        }
+        Err(git2::Error::from_str("authentication failed"))
+    });
+    callbacks
+}
+
+/// Returns the local branch HEAD points at and its upstream branch
+fn head_and_upstream(repo: &Repository) -> Result<Option<(git2::Branch<'_>, git2::Branch<'_>)>> {
+    let head = repo.head().context("Failed to read HEAD")?;
+    let Some(name) = head.shorthand() else {
+        return Ok(None);
+    };
+    let branch = repo.find_branch(name, BranchType::Local)?;
+    match branch.upstream() {
+        Ok(upstream) => Ok(Some((branch, upstream))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The files touched by `diff`, in the categories of `ChangedFiles`
+fn diff_files(diff: &mut git2::Diff) -> Result<ChangedFiles> {
+    diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+
+    let mut files = ChangedFiles::default();
+    for delta in diff.deltas() {
+        let path_of = |file: git2::DiffFile| {
+            file.path()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let from = path_of(delta.old_file());
+        let to = path_of(delta.new_file());
+
+        match delta.status() {
+            Delta::Deleted => files.deleted.push(from),
+            // a copy leaves the source untouched, so it is just an added file
+            Delta::Added | Delta::Copied => files.added.push(to),
+            Delta::Renamed => files.renamed.push(RenamedFile { from, to }),
+            _ => files.modified.push(to),
+        }
+    }
+
+    Ok(files)
+}
+
+fn diff_patch(diff: &git2::Diff) -> Result<String> {
+    let mut patch = Vec::new();
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin() as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    })?;
+
+    Ok(String::from_utf8_lossy(&patch).trim().to_string())
+}
+
+/// The tree of commit `base`, None for an unborn branch
+fn base_tree<'a>(repo: &'a Repository, base: Option<&str>) -> Result<Option<git2::Tree<'a>>> {
+    match base {
+        Some(base) => Ok(Some(repo.find_commit(Oid::from_str(base)?)?.tree()?)),
+        None => Ok(None),
+    }
+}
+
+/// How HEAD relates to the remote branch `upstream`, along with its commit
+fn analyse_upstream<'a>(
+    repo: &'a Repository,
+    upstream: &str,
+) -> Result<(MergeAnalysis, git2::Commit<'a>)> {
+    let upstream_ref = repo.find_branch(upstream, BranchType::Remote)?;
+    let theirs = repo.reference_to_annotated_commit(upstream_ref.get())?;
+    let (analysis, _) = repo.merge_analysis(&[&theirs])?;
+    Ok((analysis, upstream_ref.get().peel_to_commit()?))
+}
+
+/// Checks out `target` and moves the checked out branch to it. The checkout is
+/// safe, so it fails instead of overwriting local changes.
+fn move_head_to(repo: &Repository, target: &git2::Commit, message: &str) -> Result<()> {
+    repo.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    repo.head()?.set_target(target.id(), message)?;
+    Ok(())
+}
+
+/// Stashes changes to tracked files while `f` runs, like `git rebase --autostash`
+fn with_autostash<T>(repo_path: &Path, f: impl FnOnce(&Repository) -> Result<T>) -> Result<T> {
+    let mut repo = open(repo_path)?;
+    let signature = repo.signature()?;
+    let stashed = match repo.stash_save(&signature, "watchers: autostash", None) {
+        Ok(_) => true,
+        // nothing to stash
+        Err(e) if e.code() == git2::ErrorCode::NotFound => false,
+        Err(e) => return Err(e.into()),
+    };
+
+    let result = f(&repo);
+    if stashed {
+        let mut opts = StashApplyOptions::new();
+        opts.reinstantiate_index();
+        repo.stash_pop(0, Some(&mut opts))
+            .context("Failed to restore local changes, they are still in the stash")?;
+    }
+    result
+}
+
+/// Returns false after aborting if there were conflicts
+fn rebase_onto(repo: &Repository, upstream: &str) -> Result<bool> {
+    let signature = repo.signature()?;
+    let upstream_ref = repo.find_branch(upstream, BranchType::Remote)?;
+    let onto = repo.reference_to_annotated_commit(upstream_ref.get())?;
+
+    let mut rebase = repo.rebase(None, Some(&onto), None, None)?;
+    while let Some(op) = rebase.next() {
+        // a failure to apply the commit rather than a conflict
+        if let Err(e) = op {
+            rebase.abort()?;
+            return Err(e).context("Rebase failed");
+        }
+        if repo.index()?.has_conflicts() {
+            debug!("Rebase hit conflicts, aborting");
+            rebase.abort()?;
+            return Ok(false);
+        }
+        if let Err(e) = rebase.commit(None, &signature, None) {
+            // commits that become empty after rebasing are skipped, like the CLI does
+            if e.code() != git2::ErrorCode::Applied {
+                rebase.abort()?;
+                return Err(e).context("Rebase failed");
+            }
+        }
+    }
+    rebase.finish(Some(&signature))?;
+    Ok(true)
+}
+
+/// Pushes `refspec` and fails if the remote rejects it, which libgit2 only reports
+/// through a callback
+fn push_refspec(repo: &Repository, remote: &str, refspec: &str) -> Result<()> {
+    let rejection = RefCell::new(None);
+    let mut callbacks = remote_callbacks(repo);
+    callbacks.push_update_reference(|refname, status| {
+        if let Some(status) = status {
+            *rejection.borrow_mut() = Some(format!("{} rejected: {}", refname, status));
+        }
+        Ok(())
+    });
+
+    let mut remote = repo.find_remote(remote)?;
+    let mut opts = PushOptions::new();
+    opts.remote_callbacks(callbacks);
+    remote.push(&[refspec], Some(&mut opts))?;
+    drop(opts);
+
+    if let Some(rejection) = rejection.into_inner() {
+        anyhow::bail!("git push failed: {}", rejection);
+    }
+    Ok(())
+}
+
+fn remote_name_for(repo: &Repository, branch: &git2::Branch) -> Result<String> {
+    let refname = branch.get().name().context("Invalid branch name")?;
+    let remote = repo.branch_upstream_remote(refname)?;
+    Ok(remote.as_str().context("Invalid remote name")?.to_string())
+}
+
+/// Stages new, modified and deleted files matching `pathspecs` that `filter` doesn't skip
+fn stage_matching(
+    repo_path: &Path,
+    pathspecs: &[&str],
+    mut filter: Option<&mut IndexMatchedPath>,
+) -> Result<()> {
+    let repo = open(repo_path)?;
+    let mut index = repo.index()?;
+    // add_all picks up new and modified files, update_all removes deleted ones
+    index.add_all(pathspecs, IndexAddOption::DEFAULT, filter.as_deref_mut())?;
+    index.update_all(pathspecs, filter)?;
+    index.write()?;
+    Ok(())
+}
+
+impl GitBackend for NativeBackend {
+    fn is_repo(&self, repo_path: &Path) -> bool {
+        Repository::discover(repo_path).is_ok()
+    }
+
+    fn git_dir(&self, repo_path: &Path) -> Result<PathBuf> {
+        Ok(open(repo_path)?.path().to_path_buf())
+    }
+
+    fn workdir(&self, repo_path: &Path) -> Result<PathBuf> {
+        workdir(&open(repo_path)?)
+    }
+
+    fn init(&self, repo_path: &Path) -> Result<()> {
+        Repository::init(repo_path)?;
+        Ok(())
+    }
+
+    fn clone(&self, url: &str, repo_path: &Path) -> Result<()> {
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(default_remote_callbacks());
+        RepoBuilder::new()
+            .fetch_options(fetch_options)
+            .clone(url, repo_path)
+            .with_context(|| format!("Failed to clone {} into {}", url, repo_path.display()))?;
+        Ok(())
+    }
+
+    fn changed_files(&self, repo_path: &Path) -> Result<ChangedFiles> {
+        let repo = open(repo_path)?;
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .include_ignored(false)
+            .renames_head_to_index(true);
+        let statuses = repo.statuses(Some(&mut opts))?;
+        let mut files = ChangedFiles::default();
+
+        for entry in statuses.iter() {
+            let status = entry.status();
+            if status.is_ignored() || status == Status::CURRENT {
+                continue;
+            }
+
+            // mirror the categories the CLI backend derives from `git status --porcelain=v2`
+            if status.is_index_renamed()
+                && let Some(delta) = entry.head_to_index()
+                && let (Some(from), Some(to)) = (delta.old_file().path(), delta.new_file().path())
+            {
+                files.renamed.push(RenamedFile {
+                    from: from.to_string_lossy().to_string(),
+                    to: to.to_string_lossy().to_string(),
+                });
+                continue;
+            }
+
+            let Some(path) = entry.path() else {
+                continue;
+            };
+            let path = path.to_string();
+            if status.is_wt_deleted() || status.is_index_deleted() {
+                files.deleted.push(path);
+            } else if status.is_wt_new() || status.is_index_new() {
+                files.added.push(path);
+            } else {
+                files.modified.push(path);
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn staged_files(&self, repo_path: &Path) -> Result<ChangedFiles> {
+        let repo = open(repo_path)?;
+        let head_tree = match repo.head() {
+            Ok(head) => Some(head.peel_to_tree()?),
+            Err(_) => None,
+        };
+        let mut diff = repo.diff_tree_to_index(head_tree.as_ref(), None, None)?;
+        diff_files(&mut diff)
+    }
+
+    fn stage_all(&self, repo_path: &Path) -> Result<()> {
+        stage_matching(repo_path, &["*"], None)
+    }
+
+    fn stage_paths(&self, repo_path: &Path, paths: &[&str]) -> Result<()> {
+        // the paths are patterns to libgit2, so skip anything else they match
+        let mut exact = |path: &Path, _: &[u8]| !paths.iter().any(|p| path == Path::new(p)) as i32;
+        stage_matching(repo_path, paths, Some(&mut exact))
+    }
+
+    fn staged_diff(&self, repo_path: &Path) -> Result<String> {
+        let repo = open(repo_path)?;
+        let head_tree = match repo.head() {
+            Ok(head) => Some(head.peel_to_tree()?),
+            Err(_) => None,
+        };
+        let diff = repo.diff_tree_to_index(head_tree.as_ref(), None, None)?;
+        diff_patch(&diff)
+    }
+
+    fn commit(&self, repo_path: &Path, message: &str) -> Result<String> {
+        let repo = open(repo_path)?;
+        let signature = repo.signature().context("Failed to get commit signature")?;
+
+        let mut index = repo.index()?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        // an unborn HEAD has no parent commit
+        let parent = match repo.head() {
+            Ok(head) => Some(head.peel_to_commit()?),
+            Err(_) => None,
+        };
+        let parents = parent.iter().collect::<Vec<_>>();
+
+        let oid = repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?;
+        Ok(oid.to_string())
+    }
+
+    fn fetch(&self, repo_path: &Path) -> Result<()> {
+        let repo = open(repo_path)?;
+        let remote_name = match head_and_upstream(&repo)? {
+            Some((branch, _)) => remote_name_for(&repo, &branch)?,
+            None => "origin".to_string(),
+        };
+
+        let mut remote = repo.find_remote(&remote_name)?;
+        let mut opts = FetchOptions::new();
+        opts.remote_callbacks(remote_callbacks(&repo));
+        remote.fetch::<&str>(&[], Some(&mut opts), None)?;
+        Ok(())
+    }
+
+    fn upstream(&self, repo_path: &Path) -> Result<Option<String>> {
+        let repo = open(repo_path)?;
+        Ok(match head_and_upstream(&repo)? {
+            Some((_, upstream)) => upstream.name()?.map(str::to_string),
+            None => None,
+        })
+    }
+
+    fn rebase(&self, repo_path: &Path, upstream: &str) -> Result<bool> {
+        // libgit2 refuses to rebase with local changes, e.g. to excluded files
+        with_autostash(repo_path, |repo| rebase_onto(repo, upstream))
+    }
+
+    fn merge(&self, repo_path: &Path, upstream: &str) -> Result<bool> {
+        with_autostash(repo_path, |repo| {
+            let (analysis, theirs) = analyse_upstream(repo, upstream)?;
+            if analysis.is_up_to_date() {
+                return Ok(true);
+            }
+            if analysis.is_fast_forward() {
+                move_head_to(repo, &theirs, "merge: Fast-forward")?;
+                return Ok(true);
+            }
+
+            // merged in memory, so conflicts leave the working tree alone
+            let ours = repo.head()?.peel_to_commit()?;
+            let mut index = repo.merge_commits(&ours, &theirs, None)?;
+            if index.has_conflicts() {
+                debug!("Merge hit conflicts, aborting");
+                return Ok(false);
+            }
+
+            let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+            let signature = repo.signature()?;
+            let message = format!("Merge remote-tracking branch '{}'", upstream);
+            let oid = repo.commit(
+                None,
+                &signature,
+                &signature,
+                &message,
+                &tree,
+                &[&ours, &theirs],
+            )?;
+            move_head_to(repo, &repo.find_commit(oid)?, &message)?;
+            Ok(true)
+        })
+    }
+
+    fn fast_forward(&self, repo_path: &Path, upstream: &str) -> Result<bool> {
+        with_autostash(repo_path, |repo| {
+            let (analysis, theirs) = analyse_upstream(repo, upstream)?;
+            if analysis.is_up_to_date() {
+                return Ok(true);
+            }
+            if !analysis.is_fast_forward() {
+                return Ok(false);
+            }
+            move_head_to(repo, &theirs, "merge: Fast-forward")?;
+            Ok(true)
+        })
+    }
+
+    fn push(&self, repo_path: &Path) -> Result<()> {
+        let repo = open(repo_path)?;
+        let head = repo.head().context("Failed to read HEAD")?;
+        let local_ref = head.name().context("Invalid branch name")?;
+
+        // read the tracking config directly, the remote branch may not have been fetched yet
+        let remote_name = repo
+            .branch_upstream_remote(local_ref)
+            .context("The current branch has no upstream branch")?;
+        let remote_name = remote_name.as_str().context("Invalid remote name")?;
+        let merge_ref = repo.branch_upstream_merge(local_ref)?;
+        let merge_ref = merge_ref.as_str().context("Invalid upstream name")?;
+        push_refspec(&repo, remote_name, &format!("{}:{}", local_ref, merge_ref))
+    }
+
+    fn ahead_behind(&self, repo_path: &Path) -> Result<Option<(usize, usize)>> {
+        let repo = open(repo_path)?;
+        let Some((branch, upstream)) = head_and_upstream(&repo)? else {
+            return Ok(None);
+        };
+
+        let (Some(local), Some(remote)) = (branch.get().target(), upstream.get().target()) else {
+            return Ok(None);
+        };
+        Ok(Some(repo.graph_ahead_behind(local, remote)?))
+    }
+
+    fn remotes(&self, repo_path: &Path) -> Result<Vec<String>> {
+        let repo = open(repo_path)?;
+        Ok(repo
+            .remotes()?
+            .iter()
+            .flatten()
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn submodule_paths(&self, repo_path: &Path) -> Result<Vec<String>> {
+        let repo = open(repo_path)?;
+        Ok(repo
+            .submodules()?
+            .iter()
+            .map(|sm| sm.path().to_string_lossy().to_string())
+            .collect())
+    }
+
+    fn current_branch(&self, repo_path: &Path) -> Result<Option<String>> {
+        let repo = open(repo_path)?;
+        // read HEAD itself, resolving it fails on an unborn branch
+        let head = repo.find_reference("HEAD")?;
+        Ok(head
+            .symbolic_target()
+            .and_then(|target| target.strip_prefix("refs/heads/"))
+            .map(str::to_string))
+    }
+
+    fn resolve(&self, repo_path: &Path, rev: &str) -> Result<Option<String>> {
+        let repo = open(repo_path)?;
+        Ok(repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit())
+            .ok()
+            .map(|commit| commit.id().to_string()))
+    }
+
+    fn snapshot_tree(
+        &self,
+        repo_path: &Path,
+        base: Option<&str>,
+        keep: &dyn Fn(&str) -> bool,
+    ) -> Result<String> {
+        let repo = open(repo_path)?;
+        let mut index = Index::new()?;
+        if let Some(tree) = base_tree(&repo, base)? {
+            index.read_tree(&tree)?;
+        }
+        // an index that is never written, set on this handle only so adding can
+        // read the working tree and ignore rules
+        repo.set_index(&mut index)?;
+
+        let mut matched = |path: &Path, _: &[u8]| {
+            if keep(&path.to_string_lossy()) { 0 } else { 1 }
+        };
+        index.add_all(["*"], IndexAddOption::DEFAULT, Some(&mut matched))?;
+        index.update_all(["*"], Some(&mut matched))?;
+        Ok(index.write_tree()?.to_string())
+    }
+
+    fn tree_changes(
+        &self,
+        repo_path: &Path,
+        base: Option<&str>,
+        tree: &str,
+    ) -> Result<ChangedFiles> {
+        let repo = open(repo_path)?;
+        let tree = repo.find_tree(Oid::from_str(tree)?)?;
+        let base = base_tree(&repo, base)?;
+        let mut diff = repo.diff_tree_to_tree(base.as_ref(), Some(&tree), None)?;
+        diff_files(&mut diff)
+    }
+
+    fn tree_diff(&self, repo_path: &Path, base: Option<&str>, tree: &str) -> Result<String> {
+        let repo = open(repo_path)?;
+        let tree = repo.find_tree(Oid::from_str(tree)?)?;
+        let base = base_tree(&repo, base)?;
+        let diff = repo.diff_tree_to_tree(base.as_ref(), Some(&tree), None)?;
+        diff_patch(&diff)
+    }
+
+    fn commit_tree(
+        &self,
+        repo_path: &Path,
+        tree: &str,
+        parent: Option<&str>,
+        message: &str,
+    ) -> Result<String> {
+        let repo = open(repo_path)?;
+        let signature = repo.signature().context("Failed to get commit signature")?;
+        let tree = repo.find_tree(Oid::from_str(tree)?)?;
+        let parent = match parent {
+            Some(parent) => Some(repo.find_commit(Oid::from_str(parent)?)?),
+            None => None,
+        };
+        let parents = parent.iter().collect::<Vec<_>>();
+
+        let oid = repo.commit(None, &signature, &signature, message, &tree, &parents)?;
+        Ok(oid.to_string())
+    }
+
+    fn update_ref(
+        &self,
+        repo_path: &Path,
+        refname: &str,
+        new: &str,
+        old: Option<&str>,
+    ) -> Result<()> {
+        let repo = open(repo_path)?;
+        let new = Oid::from_str(new)?;
+        let log_message = "watchers: snapshot";
+        match old {
+            Some(old) => {
+                repo.reference_matching(refname, new, true, Oid::from_str(old)?, log_message)?
+            }
+            // not forcing fails if the ref was created in the meantime
+            None => repo.reference(refname, new, false, log_message)?,
+        };
+        Ok(())
+    }
+
+    fn push_ref(&self, repo_path: &Path, remote: &str, refname: &str) -> Result<()> {
+        let repo = open(repo_path)?;
+        push_refspec(&repo, remote, &format!("{}:{}", refname, refname))
+    }
+}
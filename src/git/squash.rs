@@ -0,0 +1,148 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use super::{
+    AUTOSAVE_TRAILER, GitBackend, cli::CliBackend, get_commit_message, git, git_raw, lock_repo,
+    with_trailer,
+};
+use crate::{config::Config, output::ErrorCode};
+
+pub struct SquashOptions {
+    /// Only squash commits made after this revision
+    pub since: Option<String>,
+    /// Message of the squashed commit instead of a summary of the combined changes
+    pub message: Option<String>,
+    /// Squash commits that were already pushed
+    pub force: bool,
+}
+
+/// What `watchers squash` did in one repository.
+#[derive(Debug, Serialize)]
+pub struct SquashResult {
+    pub path: PathBuf,
+    /// How many commits were replaced, 0 if there was nothing to squash
+    pub squashed: usize,
+    /// The commit that replaced them
+    pub commit: Option<String>,
+}
+
+/// The consecutive commits made by watcher `name` at the tip of HEAD, newest first
+fn autosave_run(repo_path: &Path, name: &str, since: Option<&str>) -> Result<Vec<String>> {
+    let range = match since {
+        Some(since) => format!("{}..HEAD", since),
+        None => "HEAD".to_string(),
+    };
+    let format = format!(
+        "--format=%H %P%n%(trailers:key={},valueonly)",
+        AUTOSAVE_TRAILER
+    );
+    let output = git_raw(repo_path, &["log", "--first-parent", "-z", &format, &range])?;
+
+    let mut run = Vec::new();
+    for entry in output.split('\0').filter(|e| !e.is_empty()) {
+        let mut lines = entry.lines();
+        let mut ids = lines.next().unwrap_or_default().split_whitespace();
+        let Some(hash) = ids.next() else {
+            break;
+        };
+        let parents = ids.count();
+
+        // merges can't be squashed without losing one side of their history
+        if parents > 1 || !lines.any(|value| value.trim() == name) {
+            break;
+        }
+        run.push(hash.to_string());
+    }
+
+    Ok(run)
+}
+
+/// Replaces the autosave commits at the tip of the checked out branch with a
+/// single commit of the same tree. The working tree and index are left alone.
+pub fn squash_autosaves(
+    config: &Config,
+    repo_path: &Path,
+    options: &SquashOptions,
+) -> Result<SquashResult> {
+    let mut result = SquashResult {
+        path: repo_path.to_path_buf(),
+        squashed: 0,
+        commit: None,
+    };
+
+    if let Some(since) = &options.since {
+        let rev = format!("{}^{{commit}}", since);
+        git(repo_path, &["rev-parse", "--verify", "--quiet", &rev])
+            .map_err(|_| ErrorCode::InvalidInput.err(format!("'{}' isn't a commit", since)))?;
+    }
+
+    // keep the watcher from committing while the branch is rewritten
+    let _lock = lock_repo(&CliBackend, repo_path)?;
+    let head = git(repo_path, &["rev-parse", "HEAD"])?;
+    let run = autosave_run(repo_path, &config.name, options.since.as_deref())?;
+    let Some(oldest) = run.last().filter(|_| run.len() > 1) else {
+        return Ok(result);
+    };
+
+    if !options.force {
+        // a remote branch containing the oldest commit contains the ones after it too
+        let pushed_to = git(
+            repo_path,
+            &[
+                "for-each-ref",
+                "--contains",
+                oldest,
+                "--format=%(refname:short)",
+                "refs/remotes",
+            ],
+        )?;
+        if let Some(remote_ref) = pushed_to.lines().next() {
+            anyhow::bail!(
+                "The autosave commits were already pushed to {}, use --since to only squash \
+                 newer ones or --force to rewrite them anyway",
+                remote_ref
+            );
+        }
+    }
+
+    // None when the oldest autosave is the root commit
+    let base = git(
+        repo_path,
+        &["rev-parse", "--verify", "--quiet", &format!("{}^", oldest)],
+    )
+    .ok();
+    let tree = git(repo_path, &["rev-parse", "HEAD^{tree}"])?;
+
+    let message = match &options.message {
+        Some(message) => message.clone(),
+        None => {
+            let changed_files = CliBackend.tree_changes(repo_path, base.as_deref(), &tree)?;
+            get_commit_message(&changed_files, config)
+        }
+    };
+    let message = with_trailer(&message, &config.name);
+
+    let mut args = vec!["commit-tree", &tree, "-m", &message];
+    if let Some(base) = &base {
+        args.extend(["-p", base]);
+    }
+    let commit = git(repo_path, &args)?;
+
+    // fails if the watcher committed in the meantime, rather than dropping that commit
+    git(
+        repo_path,
+        &[
+            "update-ref",
+            "-m",
+            "watchers: squash",
+            "HEAD",
+            &commit,
+            &head,
+        ],
+    )?;
+
+    result.squashed = run.len();
+    result.commit = Some(commit);
+    Ok(result)
+}
@@ -0,0 +1,518 @@
+//! Every check runs against each backend, so they stay interchangeable.
+
+use std::{fs, path::Path};
+
+use tempfile::TempDir;
+
+use super::{ChangedFiles, GitBackend, cli::CliBackend, git};
+
+/// Long enough for git to recognise a rename after a small edit
+const CONTENT: &str = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\n";
+
+fn backends() -> Vec<(&'static str, &'static dyn GitBackend)> {
+    vec![
+        ("cli", &CliBackend),
+        #[cfg(feature = "native-git")]
+        ("native", &super::native::NativeBackend),
+    ]
+}
+
+fn write(repo: &Path, path: &str, content: &str) {
+    let path = repo.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}
+
+fn run(repo: &Path, args: &[&str]) -> String {
+    git(repo, args).unwrap()
+}
+
+/// Sets an identity in the repository itself, libgit2 doesn't read the environment
+fn configure(repo: &Path) {
+    run(repo, &["config", "user.name", "Test"]);
+    run(repo, &["config", "user.email", "test@example.com"]);
+    run(repo, &["config", "commit.gpgsign", "false"]);
+}
+
+/// A repository on `main` with one commit of `files`
+fn repo_with(files: &[(&str, &str)]) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    run(dir.path(), &["init", "-q", "-b", "main"]);
+    configure(dir.path());
+    for (path, content) in files {
+        write(dir.path(), path, content);
+    }
+    run(dir.path(), &["add", "-A"]);
+    run(dir.path(), &["commit", "-q", "-m", "init"]);
+    dir
+}
+
+/// A bare remote holding `f`, and two clones of it tracking `origin/main`
+fn remote_and_clones() -> (TempDir, TempDir, TempDir) {
+    let origin = repo_with(&[("f", CONTENT)]);
+    let remote = TempDir::new().unwrap();
+    let bare = remote.path().to_string_lossy().to_string();
+    run(origin.path(), &["clone", "-q", "--bare", ".", &bare]);
+
+    let clone = || {
+        let dir = TempDir::new().unwrap();
+        run(dir.path(), &["clone", "-q", &bare, "."]);
+        configure(dir.path());
+        dir
+    };
+    (remote, clone(), clone())
+}
+
+/// Commits `content` to `path` in `repo` and pushes it
+fn push_change(repo: &Path, path: &str, content: &str) {
+    write(repo, path, content);
+    run(repo, &["commit", "-q", "-am", "change"]);
+    run(repo, &["push", "-q"]);
+}
+
+/// The changes as sorted `<kind> <path>` lines, which compare the same across backends
+fn summary(files: &ChangedFiles) -> Vec<String> {
+    let mut lines = files
+        .deleted
+        .iter()
+        .map(|p| format!("D {}", p))
+        .chain(files.modified.iter().map(|p| format!("M {}", p)))
+        .chain(files.added.iter().map(|p| format!("A {}", p)))
+        .chain(
+            files
+                .renamed
+                .iter()
+                .map(|r| format!("R {} -> {}", r.from, r.to)),
+        )
+        .collect::<Vec<_>>();
+    lines.sort();
+    lines
+}
+
+/// The staged paths, sorted
+fn staged_names(repo: &Path) -> Vec<String> {
+    let mut names = run(repo, &["diff", "--cached", "--name-only", "-z"])
+        .split('\0')
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn init_and_repository_paths() {
+    for (name, backend) in backends() {
+        let dir = TempDir::new().unwrap();
+        assert!(!backend.is_repo(dir.path()), "{}", name);
+        backend.init(dir.path()).unwrap();
+        assert!(backend.is_repo(dir.path()), "{}", name);
+
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        let git_dir = backend.git_dir(&sub).unwrap().canonicalize().unwrap();
+        assert_eq!(git_dir, top.join(".git"), "{}", name);
+        let workdir = backend.workdir(&sub).unwrap().canonicalize().unwrap();
+        assert_eq!(workdir, top, "{}", name);
+    }
+}
+
+#[test]
+fn changed_files_with_renames_and_unusual_paths() {
+    for (name, backend) in backends() {
+        let repo = repo_with(&[
+            ("old.txt", CONTENT),
+            ("gone.txt", "gone\n"),
+            ("ünïcode.txt", "a\n"),
+            ("with space.txt", "a\n"),
+        ]);
+        let path = repo.path();
+        run(path, &["mv", "old.txt", "new.txt"]);
+        fs::remove_file(path.join("gone.txt")).unwrap();
+        write(path, "ünïcode.txt", "b\n");
+        write(path, "with space.txt", "b\n");
+        write(path, "quote\"d.txt", "new\n");
+        write(path, "new dir/deeper/file.md", "new\n");
+
+        let files = backend.changed_files(path).unwrap();
+        assert_eq!(
+            summary(&files),
+            [
+                "A new dir/deeper/file.md",
+                "A quote\"d.txt",
+                "D gone.txt",
+                "M with space.txt",
+                "M ünïcode.txt",
+                "R old.txt -> new.txt",
+            ],
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn stage_and_commit() {
+    for (name, backend) in backends() {
+        let repo = repo_with(&[("a.txt", "a\n"), ("b.txt", CONTENT)]);
+        let path = repo.path();
+        write(path, "a.txt", "changed\n");
+        write(path, "c.txt", "new\n");
+        run(path, &["mv", "b.txt", "renamed.txt"]);
+
+        backend
+            .stage_paths(path, &["a.txt", "b.txt", "renamed.txt"])
+            .unwrap();
+        let staged = backend.staged_files(path).unwrap();
+        assert_eq!(
+            summary(&staged),
+            ["M a.txt", "R b.txt -> renamed.txt"],
+            "{}",
+            name
+        );
+        assert!(
+            backend.staged_diff(path).unwrap().contains("+changed"),
+            "{}",
+            name
+        );
+
+        let hash = backend.commit(path, "partial").unwrap();
+        assert_eq!(
+            backend.resolve(path, "HEAD").unwrap(),
+            Some(hash),
+            "{}",
+            name
+        );
+        assert_eq!(
+            summary(&backend.changed_files(path).unwrap()),
+            ["A c.txt"],
+            "{}",
+            name
+        );
+
+        backend.stage_all(path).unwrap();
+        backend.commit(path, "rest").unwrap();
+        assert!(backend.changed_files(path).unwrap().is_empty(), "{}", name);
+        assert_eq!(run(path, &["log", "-1", "--format=%s"]), "rest", "{}", name);
+        assert_eq!(
+            backend.current_branch(path).unwrap().as_deref(),
+            Some("main"),
+            "{}",
+            name
+        );
+        assert_eq!(
+            backend.resolve(path, "no-such-branch").unwrap(),
+            None,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn stage_paths_takes_names_literally() {
+    for (name, backend) in backends() {
+        let repo = repo_with(&[("ab.md", "a\n"), ("other.md", "a\n")]);
+        let path = repo.path();
+        for file in ["ab.md", "other.md", "a[bc].md", "*.md"] {
+            write(path, file, "changed\n");
+        }
+
+        backend.stage_paths(path, &["a[bc].md", "*.md"]).unwrap();
+        assert_eq!(staged_names(path), ["*.md", "a[bc].md"], "{}", name);
+    }
+}
+
+#[test]
+fn snapshot_leaves_index_and_head_alone() {
+    for (name, backend) in backends() {
+        let repo = repo_with(&[("a.txt", "a\n"), ("skip.txt", "a\n")]);
+        let path = repo.path();
+        let head = backend.resolve(path, "HEAD").unwrap().unwrap();
+        write(path, "a.txt", "b\n");
+        write(path, "skip.txt", "b\n");
+        write(path, "dir/new.txt", "new\n");
+        // a name that would reject every file if it were taken as a pattern
+        write(path, "*.txt", "new\n");
+
+        let keep = |p: &str| p != "skip.txt" && p != "*.txt";
+        let tree = backend.snapshot_tree(path, Some(&head), &keep).unwrap();
+        let changes = backend.tree_changes(path, Some(&head), &tree).unwrap();
+        assert_eq!(summary(&changes), ["A dir/new.txt", "M a.txt"], "{}", name);
+        assert!(
+            backend
+                .tree_diff(path, Some(&head), &tree)
+                .unwrap()
+                .contains("+b"),
+            "{}",
+            name
+        );
+
+        // without a base every kept file is new
+        let tree = backend.snapshot_tree(path, None, &keep).unwrap();
+        let changes = backend.tree_changes(path, None, &tree).unwrap();
+        assert_eq!(summary(&changes), ["A a.txt", "A dir/new.txt"], "{}", name);
+
+        let commit = backend
+            .commit_tree(path, &tree, Some(&head), "snapshot")
+            .unwrap();
+        backend
+            .update_ref(path, "refs/watchers/main", &commit, None)
+            .unwrap();
+        assert_eq!(
+            backend.resolve(path, "refs/watchers/main").unwrap(),
+            Some(commit.clone()),
+            "{}",
+            name
+        );
+        // the ref exists now, so creating it again fails
+        assert!(
+            backend
+                .update_ref(path, "refs/watchers/main", &head, None)
+                .is_err(),
+            "{}",
+            name
+        );
+        backend
+            .update_ref(path, "refs/watchers/main", &head, Some(&commit))
+            .unwrap();
+
+        assert_eq!(
+            backend.resolve(path, "HEAD").unwrap(),
+            Some(head),
+            "{}",
+            name
+        );
+        assert!(backend.staged_files(path).unwrap().is_empty(), "{}", name);
+    }
+}
+
+#[test]
+fn remotes_fetch_rebase_and_push() {
+    for (name, backend) in backends() {
+        let (remote, ours, theirs) = remote_and_clones();
+        let (ours, theirs) = (ours.path(), theirs.path());
+        assert_eq!(backend.remotes(ours).unwrap(), ["origin"], "{}", name);
+        assert_eq!(
+            backend.upstream(ours).unwrap().as_deref(),
+            Some("origin/main"),
+            "{}",
+            name
+        );
+        assert_eq!(
+            backend.ahead_behind(ours).unwrap(),
+            Some((0, 0)),
+            "{}",
+            name
+        );
+
+        write(theirs, "theirs.txt", "theirs\n");
+        run(theirs, &["add", "theirs.txt"]);
+        run(theirs, &["commit", "-q", "-m", "add"]);
+        run(theirs, &["push", "-q"]);
+        write(ours, "ours.txt", "ours\n");
+        run(ours, &["add", "ours.txt"]);
+        run(ours, &["commit", "-q", "-m", "ours"]);
+        backend.fetch(ours).unwrap();
+        assert_eq!(
+            run(ours, &["rev-parse", "origin/main"]),
+            run(theirs, &["rev-parse", "HEAD"]),
+            "{}",
+            name
+        );
+        assert_eq!(
+            backend.ahead_behind(ours).unwrap(),
+            Some((1, 1)),
+            "{}",
+            name
+        );
+
+        assert!(
+            backend.push(ours).is_err(),
+            "{} pushed a diverged branch",
+            name
+        );
+        assert!(backend.rebase(ours, "origin/main").unwrap(), "{}", name);
+        backend.push(ours).unwrap();
+        assert_eq!(
+            backend.ahead_behind(ours).unwrap(),
+            Some((0, 0)),
+            "{}",
+            name
+        );
+        assert_eq!(
+            run(remote.path(), &["rev-parse", "main"]),
+            run(ours, &["rev-parse", "HEAD"]),
+            "{}",
+            name
+        );
+        assert!(ours.join("theirs.txt").exists(), "{}", name);
+        assert!(
+            backend.submodule_paths(ours).unwrap().is_empty(),
+            "{}",
+            name
+        );
+
+        let head = backend.resolve(ours, "HEAD").unwrap().unwrap();
+        backend
+            .update_ref(ours, "refs/heads/side", &head, None)
+            .unwrap();
+        backend.push_ref(ours, "origin", "refs/heads/side").unwrap();
+        assert_eq!(run(remote.path(), &["rev-parse", "side"]), head, "{}", name);
+
+        let cloned = TempDir::new().unwrap();
+        let target = cloned.path().join("clone");
+        backend
+            .clone(&remote.path().to_string_lossy(), &target)
+            .unwrap();
+        assert_eq!(
+            backend.resolve(&target, "HEAD").unwrap(),
+            Some(head),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn rebase_merge_and_fast_forward() {
+    for (name, backend) in backends() {
+        // fast-forward when only the upstream moved, keeping local changes
+        let (_remote, ours, theirs) = remote_and_clones();
+        let (ours, theirs) = (ours.path(), theirs.path());
+        push_change(theirs, "f", &CONTENT.replace("one", "ONE"));
+        backend.fetch(ours).unwrap();
+        assert_eq!(
+            backend.ahead_behind(ours).unwrap(),
+            Some((0, 1)),
+            "{}",
+            name
+        );
+        write(ours, "local.txt", "untracked\n");
+        assert!(
+            backend.fast_forward(ours, "origin/main").unwrap(),
+            "{}",
+            name
+        );
+        assert_eq!(
+            backend.ahead_behind(ours).unwrap(),
+            Some((0, 0)),
+            "{}",
+            name
+        );
+        assert!(
+            fs::read_to_string(ours.join("f"))
+                .unwrap()
+                .starts_with("ONE"),
+            "{}",
+            name
+        );
+
+        // changes to different lines rebase and merge cleanly, but can't fast-forward
+        push_change(
+            theirs,
+            "f",
+            &CONTENT.replace("one", "ONE").replace("eight", "EIGHT"),
+        );
+        write(
+            ours,
+            "f",
+            &CONTENT.replace("one", "ONE").replace("two", "TWO"),
+        );
+        run(ours, &["commit", "-q", "-am", "ours"]);
+        backend.fetch(ours).unwrap();
+        assert!(
+            !backend.fast_forward(ours, "origin/main").unwrap(),
+            "{}",
+            name
+        );
+        let head = backend.resolve(ours, "HEAD").unwrap();
+        assert!(backend.merge(ours, "origin/main").unwrap(), "{}", name);
+        assert_eq!(
+            run(ours, &["rev-list", "--count", "--merges", "HEAD"]),
+            "1",
+            "{}",
+            name
+        );
+        run(ours, &["reset", "-q", "--hard", head.as_deref().unwrap()]);
+
+        // a modified tracked file doesn't stop the rebase and is kept
+        write(ours, "local.txt", "still here\n");
+        run(ours, &["add", "local.txt"]);
+        run(ours, &["commit", "-q", "-m", "track"]);
+        write(ours, "local.txt", "modified\n");
+        assert!(backend.rebase(ours, "origin/main").unwrap(), "{}", name);
+        assert_eq!(
+            backend.ahead_behind(ours).unwrap(),
+            Some((2, 0)),
+            "{}",
+            name
+        );
+        let f = fs::read_to_string(ours.join("f")).unwrap();
+        assert!(f.contains("TWO") && f.contains("EIGHT"), "{}", name);
+        assert_eq!(
+            fs::read_to_string(ours.join("local.txt")).unwrap(),
+            "modified\n",
+            "{}",
+            name
+        );
+
+        // changes to the same line conflict, and both abort leaving everything as it was
+        push_change(theirs, "f", "theirs\n");
+        run(ours, &["checkout", "-q", "--", "local.txt"]);
+        write(ours, "f", "ours\n");
+        run(ours, &["commit", "-q", "-am", "conflicting"]);
+        backend.fetch(ours).unwrap();
+        let head = backend.resolve(ours, "HEAD").unwrap();
+        assert!(!backend.rebase(ours, "origin/main").unwrap(), "{}", name);
+        assert!(!backend.merge(ours, "origin/main").unwrap(), "{}", name);
+        assert_eq!(backend.resolve(ours, "HEAD").unwrap(), head, "{}", name);
+        assert_eq!(
+            fs::read_to_string(ours.join("f")).unwrap(),
+            "ours\n",
+            "{}",
+            name
+        );
+        assert!(backend.changed_files(ours).unwrap().is_empty(), "{}", name);
+    }
+}
+
+#[test]
+fn merge_and_fast_forward_keep_local_edits() {
+    for (name, backend) in backends() {
+        for fast_forward in [false, true] {
+            // an uncommitted edit to a file the upstream changed elsewhere
+            let (_remote, ours, theirs) = remote_and_clones();
+            let (ours, theirs) = (ours.path(), theirs.path());
+            push_change(theirs, "f", &CONTENT.replace("eight", "EIGHT"));
+            write(ours, "f", &CONTENT.replace("one", "ONE"));
+            backend.fetch(ours).unwrap();
+
+            let synced = if fast_forward {
+                backend.fast_forward(ours, "origin/main")
+            } else {
+                backend.merge(ours, "origin/main")
+            };
+            assert!(synced.unwrap(), "{} fast_forward={}", name, fast_forward);
+            assert_eq!(
+                backend.ahead_behind(ours).unwrap(),
+                Some((0, 0)),
+                "{}",
+                name
+            );
+            assert_eq!(
+                fs::read_to_string(ours.join("f")).unwrap(),
+                CONTENT.replace("one", "ONE").replace("eight", "EIGHT"),
+                "{}",
+                name
+            );
+            assert_eq!(
+                summary(&backend.changed_files(ours).unwrap()),
+                ["M f"],
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -1,67 +1,297 @@
+mod check;
 mod cli;
 mod config;
+mod control;
 mod debouncer;
+mod discover;
 mod file_utils;
+mod filter;
 mod git;
+mod network;
+mod output;
+mod pause;
+mod push_queue;
+mod state;
 mod systemd;
+mod template;
 mod watcher;
 
 use anyhow::Result;
 use clap::Parser;
 
 use crate::{
-    cli::{Cli, Commands},
-    git::handle_event,
+    check::check_config,
+    cli::{Cli, Commands, ConfigCommands},
+    config::{Config, config_schema},
+    control::{Request, is_running, send_request},
+    git::{SquashOptions, handle_event},
+    output::{Action, ActionOutput, ErrorCode, OutputFormat, print_error},
+    pause::PauseState,
+    systemd::SystemdContext,
     watcher::{
-        Watcher, create_watcher, delete_watcher, get_watcher_config, get_watcher_logs,
-        list_watchers, run_daemon, start_watcher, stop_watcher, trigger_watcher,
+        CreateOptions, EditOutcome, Watcher, create_watcher, delete_watcher, edit_watcher,
+        get_watcher_config, get_watcher_logs, get_watcher_names, get_watcher_status, run_daemon,
+        squash_watcher, start_watcher, stop_watcher, trigger_watcher,
     },
 };
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     env_logger::init();
     let cli = Cli::parse();
 
+    let output = cli.output_format();
+    if let Err(e) = run(cli, output).await {
+        print_error(output, &e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli, output: OutputFormat) -> Result<()> {
     match &cli.command {
         Commands::Start { name } => {
             start_watcher(name).await?;
-            println!("Successfully started watcher '{}'", name);
+            print_action(output, name, Action::Started, || {
+                format!("Successfully started watcher '{}'", name)
+            })?;
         }
 
         Commands::Stop { name } => {
             stop_watcher(name).await?;
-            println!("Successfully stopped watcher '{}'", name);
+            print_action(output, name, Action::Stopped, || {
+                format!("Successfully stopped watcher '{}'", name)
+            })?;
         }
 
-        Commands::Create { name } => {
-            create_watcher(name).await?;
-            println!(
-                "Successfully created watcher '{}', start it with: 'watchers start {}",
-                name, name
-            );
+        Commands::Create {
+            name,
+            paths,
+            delay,
+            init,
+            clone_url,
+            no_push,
+            force,
+            no_start,
+        } => {
+            let options = CreateOptions {
+                paths: paths.clone(),
+                delay_secs: *delay,
+                init: *init,
+                clone_url: clone_url.clone(),
+                no_push: *no_push,
+                force: *force,
+                no_start: *no_start,
+            };
+            create_watcher(name, &options).await?;
+            print_action(output, name, Action::Created, || {
+                if *no_start {
+                    format!(
+                        "Successfully created watcher '{}', start it with: 'watchers start {}'",
+                        name, name
+                    )
+                } else {
+                    format!("Successfully created and started watcher '{}'", name)
+                }
+            })?;
         }
 
         Commands::Delete { name } => {
             delete_watcher(name).await?;
-            println!("Successfully deleted watcher '{}'", name);
+            print_action(output, name, Action::Deleted, || {
+                format!("Successfully deleted watcher '{}'", name)
+            })?;
+        }
+
+        Commands::Edit { name } => {
+            let outcome = edit_watcher(name).await?;
+            print_action(output, name, Action::Edited, || match outcome {
+                EditOutcome::Unchanged => format!("No changes to watcher '{}'", name),
+                EditOutcome::Saved => format!("Saved config for watcher '{}'", name),
+                EditOutcome::Reloaded => {
+                    format!("Saved and reloaded config for watcher '{}'", name)
+                }
+                EditOutcome::Restarted => {
+                    format!("Saved config and restarted watcher '{}'", name)
+                }
+            })?;
         }
 
         Commands::List {} => {
-            list_watchers()?;
+            let names = get_watcher_names()?;
+            output::print(output, &names, || {
+                let mut text = "Watchers:".to_string();
+                for name in &names {
+                    text.push_str(&format!("\n  {}", name));
+                }
+                text
+            })?;
+        }
+
+        Commands::Status { name, .. } => {
+            let names = match name {
+                Some(name) => vec![name.clone()],
+                None => get_watcher_names()?,
+            };
+
+            // still show what we can when there's no systemd user session
+            let systemd_ctx = SystemdContext::new()
+                .await
+                .inspect_err(|e| log::debug!("Failed to connect to systemd: {}", e))
+                .ok();
+            let mut statuses = Vec::new();
+            for watcher in &names {
+                match get_watcher_status(watcher, systemd_ctx.as_ref()).await {
+                    Ok(status) => statuses.push(status),
+                    // one broken config shouldn't hide the other watchers
+                    Err(e) if name.is_none() => {
+                        log::error!("Failed to get status of '{}': {:#}", watcher, e)
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            output::print(output, &statuses, || {
+                statuses
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+                    .trim_end()
+                    .to_string()
+            })?;
         }
 
         Commands::Logs { name } => {
-            println!("{}", get_watcher_logs(name).await?);
+            let logs = get_watcher_logs(name).await?;
+            output::print(
+                output,
+                &serde_json::json!({ "name": name, "logs": logs }),
+                || logs.clone(),
+            )?;
         }
 
         Commands::Trigger { name } => {
             let config = get_watcher_config(name)?;
-            let mut watcher = Watcher::new(&config, |context| {
-                handle_event(context);
-            });
-            trigger_watcher(&mut watcher);
-            println!("Successfully triggered watcher '{}'", name);
+            if is_running(name).await {
+                send_request(name, &Request::Trigger).await?.into_result()?;
+            } else {
+                // nothing to race with, so commit from this process
+                let watcher = Watcher::new(config, |context| {
+                    handle_event(context);
+                })?;
+                trigger_watcher(&watcher).await;
+            }
+            print_action(output, name, Action::Triggered, || {
+                format!("Successfully triggered watcher '{}'", name)
+            })?;
+        }
+
+        Commands::Pause { name, duration } => {
+            get_watcher_config(name)?;
+            if is_running(name).await {
+                send_request(name, &Request::pause(*duration))
+                    .await?
+                    .into_result()?;
+            } else {
+                // picked up when the daemon starts
+                let mut pause = PauseState::load(name);
+                pause.pause(*duration);
+                pause.save(name)?;
+            }
+            print_action(output, name, Action::Paused, || match duration {
+                Some(duration) => format!(
+                    "Paused watcher '{}' for {}",
+                    name,
+                    humantime::format_duration(*duration)
+                ),
+                None => format!("Paused watcher '{}'", name),
+            })?;
+        }
+
+        Commands::Resume { name } => {
+            get_watcher_config(name)?;
+            if is_running(name).await {
+                send_request(name, &Request::Resume).await?.into_result()?;
+            } else {
+                PauseState::default().save(name)?;
+            }
+            print_action(output, name, Action::Resumed, || {
+                format!("Resumed watcher '{}'", name)
+            })?;
+        }
+
+        Commands::Squash {
+            name,
+            since,
+            message,
+            force,
+        } => {
+            let options = SquashOptions {
+                since: since.clone(),
+                message: message.clone(),
+                force: *force,
+            };
+            let results = squash_watcher(name, &options)?;
+            output::print(output, &results, || {
+                results
+                    .iter()
+                    .map(|r| match &r.commit {
+                        Some(commit) => format!(
+                            "{}: squashed {} commits into {}",
+                            r.path.display(),
+                            r.squashed,
+                            &commit[..commit.len().min(7)]
+                        ),
+                        None => format!("{}: nothing to squash", r.path.display()),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })?;
+        }
+
+        Commands::Reload { name } => {
+            get_watcher_config(name)?;
+            send_request(name, &Request::ReloadConfig)
+                .await?
+                .into_result()?;
+            print_action(output, name, Action::Reloaded, || {
+                format!("Reloaded config for watcher '{}'", name)
+            })?;
+        }
+
+        Commands::Config {
+            command: ConfigCommands::Check { name },
+        } => {
+            let names = match name {
+                Some(name) => {
+                    if !Config::get_watcher_config_path(name).is_file() {
+                        return Err(ErrorCode::NotFound
+                            .err(format!("Could not find config for '{}'", name)));
+                    }
+                    vec![name.clone()]
+                }
+                None => get_watcher_names()?,
+            };
+
+            let reports = names.iter().map(|n| check_config(n)).collect::<Vec<_>>();
+            output::print(output, &reports, || {
+                reports
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })?;
+
+            if !reports.iter().all(|r| r.is_valid()) {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Config {
+            command: ConfigCommands::Schema,
+        } => {
+            println!("{}", serde_json::to_string_pretty(&config_schema())?);
         }
 
         Commands::Daemon { name } => {
@@ -71,3 +301,12 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn print_action(
+    output: OutputFormat,
+    name: &str,
+    action: Action,
+    text: impl FnOnce() -> String,
+) -> Result<()> {
+    output::print(output, &ActionOutput { name, action }, text)
+}
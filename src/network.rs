@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+use futures_lite::StreamExt;
+use log::debug;
+use zbus::{Connection, proxy};
+
+/// `NM_STATE_CONNECTED_GLOBAL`, full internet access
+const CONNECTED_GLOBAL: u32 = 70;
+
+#[proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    #[zbus(signal)]
+    fn state_changed(&self, state: u32) -> zbus::Result<()>;
+}
+
+/// Calls `on_up` every time NetworkManager reports that the machine is back online.
+/// Fails if NetworkManager isn't available on the system bus.
+pub async fn watch_network(on_up: impl Fn()) -> Result<()> {
+    let conn = Connection::system()
+        .await
+        .context("Failed to connect to the system bus")?;
+    let network_manager = NetworkManagerProxy::new(&conn).await?;
+    let mut changes = network_manager
+        .receive_state_changed()
+        .await
+        .context("Failed to listen for NetworkManager state changes")?;
+
+    while let Some(change) = changes.next().await {
+        let state = change.args()?.state;
+        debug!("network state changed to {}", state);
+        if state == CONNECTED_GLOBAL {
+            on_up();
+        }
+    }
+    Ok(())
+}
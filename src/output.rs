@@ -0,0 +1,140 @@
+use std::fmt;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Stable identifiers for the kinds of errors a command can fail with, so
+/// scripts don't have to match on messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The watcher doesn't exist
+    NotFound,
+    /// The watcher exists and wasn't overwritten
+    AlreadyExists,
+    /// The watcher's config file couldn't be parsed or validated
+    InvalidConfig,
+    /// A command line argument or prompt answer was rejected
+    InvalidInput,
+    /// The command needs a running daemon and there is none
+    NotRunning,
+    /// Talking to the systemd user manager failed
+    Systemd,
+    Io,
+    /// Anything that doesn't fit the above
+    Other,
+}
+
+/// An error carrying an `ErrorCode`. Errors that don't have one are classified
+/// by `ErrorCode::of` instead.
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+impl ErrorCode {
+    pub fn err(self, message: impl Into<String>) -> anyhow::Error {
+        CodedError {
+            code: self,
+            message: message.into(),
+        }
+        .into()
+    }
+
+    pub fn of(e: &anyhow::Error) -> ErrorCode {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<CodedError>() {
+                return e.code;
+            }
+            if cause.is::<zbus::Error>() || cause.is::<zbus::fdo::Error>() {
+                return ErrorCode::Systemd;
+            }
+            if cause.is::<serde_yaml::Error>() {
+                return ErrorCode::InvalidConfig;
+            }
+            if cause.is::<std::io::Error>() {
+                return ErrorCode::Io;
+            }
+        }
+        ErrorCode::Other
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorOutput<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: ErrorCode,
+    message: &'a str,
+}
+
+/// Prints `value` as JSON, or the result of `text` otherwise.
+pub fn print<T: Serialize + ?Sized>(
+    format: OutputFormat,
+    value: &T,
+    text: impl FnOnce() -> String,
+) -> Result<()> {
+    match format {
+        OutputFormat::Text => println!("{}", text()),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+    }
+    Ok(())
+}
+
+pub fn print_error(format: OutputFormat, e: &anyhow::Error) {
+    match format {
+        OutputFormat::Text => eprintln!("Error: {:?}", e),
+        OutputFormat::Json => {
+            let message = format!("{:#}", e);
+            let output = ErrorOutput {
+                error: ErrorBody {
+                    code: ErrorCode::of(e),
+                    message: &message,
+                },
+            };
+            // stdout, so scripts only have to parse one stream
+            println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        }
+    }
+}
+
+/// The result of a command that acts on a single watcher.
+#[derive(Serialize)]
+pub struct ActionOutput<'a> {
+    pub name: &'a str,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Started,
+    Stopped,
+    Created,
+    Deleted,
+    Edited,
+    Triggered,
+    Paused,
+    Resumed,
+    Reloaded,
+}
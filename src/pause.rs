@@ -0,0 +1,88 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::config::get_watchers_runtime_dir;
+
+fn get_pause_file_path(name: &str) -> PathBuf {
+    get_watchers_runtime_dir().join(format!("{}.paused", name))
+}
+
+/// Whether a watcher is currently allowed to commit. It is saved to a file so a
+/// pause outlives restarts of the daemon and can be set while it isn't running.
+#[derive(Default)]
+pub struct PauseState {
+    paused: bool,
+    until: Option<SystemTime>,
+}
+
+impl PauseState {
+    /// The file holds the unix time the pause runs out at, or nothing if it doesn't.
+    pub fn load(name: &str) -> Self {
+        let Ok(contents) = fs::read_to_string(get_pause_file_path(name)) else {
+            return Self::default();
+        };
+
+        Self {
+            paused: true,
+            until: contents
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        let path = get_pause_file_path(name);
+        if !self.paused {
+            if path.exists() {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove pause file: {}", path.display()))?;
+            }
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = match self.until {
+            Some(until) => until.duration_since(UNIX_EPOCH)?.as_secs().to_string(),
+            None => String::new(),
+        };
+        fs::write(&path, contents)
+            .with_context(|| format!("Failed to write pause file: {}", path.display()))
+    }
+
+    /// Pauses committing, either until resumed or for `duration`.
+    pub fn pause(&mut self, duration: Option<Duration>) {
+        self.paused = true;
+        self.until = duration.map(|d| SystemTime::now() + d);
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.until = None;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn until(&self) -> Option<SystemTime> {
+        self.until
+    }
+
+    /// Ends the pause if its duration has run out, returning true if it did.
+    pub fn expire(&mut self) -> bool {
+        if self.until.is_some_and(|until| until <= SystemTime::now()) {
+            self.resume();
+            return true;
+        }
+        false
+    }
+}
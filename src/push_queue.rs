@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, error, info};
+use tokio::{
+    runtime::Handle,
+    sync::{Notify, watch},
+};
+
+use crate::git::{EventContext, push_changes};
+
+/// Pushes a watcher's repositories in the background, so a slow or hung remote
+/// never holds up the next commit. Each repository gets a task that pushes one
+/// at a time, and requests made while it is busy are coalesced into a single
+/// push after it. A failed push is retried with a growing delay until it succeeds.
+#[derive(Clone)]
+pub struct PushQueue {
+    runtime: Handle,
+    repos: Arc<Mutex<HashMap<PathBuf, watch::Sender<Option<EventContext>>>>>,
+    retry: Arc<Notify>,
+}
+
+/// Delay before the first retry of a failed push, doubled after every failure
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+impl PushQueue {
+    /// Must be called from within the tokio runtime
+    pub fn new() -> Self {
+        Self {
+            runtime: Handle::current(),
+            repos: Arc::new(Mutex::new(HashMap::new())),
+            retry: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Pushes the context's repository once the push before it has finished.
+    /// Can be called from outside the runtime, e.g. by a commit on the blocking pool.
+    pub fn request(&self, context: EventContext) {
+        let mut repos = self.repos.lock().unwrap();
+        let tx = repos.entry(context.repo_path.clone()).or_insert_with(|| {
+            let (tx, rx) = watch::channel(None);
+            self.runtime.spawn(run_pushes(rx, Arc::clone(&self.retry)));
+            tx
+        });
+        // replaces a request that is still waiting, with the latest config
+        tx.send_replace(Some(context));
+    }
+
+    /// Retries failed pushes right away instead of waiting for their delay,
+    /// e.g. once the network is back
+    pub fn retry_now(&self) {
+        self.retry.notify_waiters();
+    }
+}
+
+async fn run_pushes(mut rx: watch::Receiver<Option<EventContext>>, retry: Arc<Notify>) {
+    while rx.changed().await.is_ok() {
+        let Some(mut context) = rx.borrow_and_update().clone() else {
+            continue;
+        };
+
+        let mut retry_delay = FIRST_RETRY_DELAY;
+        loop {
+            let push_context = context.clone();
+            match tokio::task::spawn_blocking(move || push_changes(&push_context)).await {
+                Ok(true) => break,
+                Ok(false) => (),
+                Err(e) => error!("Push failed: {}", e),
+            }
+
+            info!(
+                "Retrying push of {} in {}",
+                context.repo_path.display(),
+                humantime::format_duration(retry_delay)
+            );
+            tokio::select! {
+                changed = rx.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    // a new commit is pushed right away, along with the failed ones
+                    if let Some(latest) = rx.borrow_and_update().clone() {
+                        context = latest;
+                    }
+                }
+                _ = tokio::time::sleep(retry_delay) => (),
+                _ = retry.notified() => debug!("retrying push early"),
+            }
+            retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
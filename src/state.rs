@@ -0,0 +1,73 @@
+use std::{collections::BTreeMap, fs, path::PathBuf, sync::Mutex};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_watchers_state_dir;
+
+/// What the daemon remembers about a watcher between runs, shown by `watchers status`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct WatcherState {
+    pub last_commit: Option<CommitRecord>,
+    pub last_push_error: Option<String>,
+    /// Repositories with commits that still have to be pushed, retried when the daemon starts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub needs_push: Vec<PathBuf>,
+    /// Branches that commits conflicting with their upstream were pushed to, by repository
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub conflict_branches: BTreeMap<PathBuf, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitRecord {
+    pub hash: String,
+    pub time: String,
+    /// The watched directory the commit was made in
+    #[serde(default)]
+    pub repo: Option<PathBuf>,
+}
+
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+
+fn get_state_file_path(name: &str) -> PathBuf {
+    get_watchers_state_dir().join(format!("{}.json", name))
+}
+
+impl WatcherState {
+    pub fn load(name: &str) -> Result<WatcherState> {
+        let path = get_state_file_path(name);
+        if !path.is_file() {
+            return Ok(WatcherState::default());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to read state from {}", path.display()))
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        let path = get_state_file_path(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write state to {}", path.display()))
+    }
+
+    /// Loads the state, applies `f` and saves it again
+    pub fn update(name: &str, f: impl FnOnce(&mut WatcherState)) -> Result<()> {
+        // directories of one watcher commit from their own threads
+        let _guard = UPDATE_LOCK.lock().unwrap();
+        let mut state = WatcherState::load(name).unwrap_or_default();
+        f(&mut state);
+        state.save(name)
+    }
+
+    pub fn remove(name: &str) -> Result<()> {
+        let path = get_state_file_path(name);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
@@ -6,28 +6,36 @@ use std::{
 
 use anyhow::{Context, Result};
 use directories::ProjectDirs;
+use serde::Serialize;
 use tokio::process::Command;
 use zbus::Connection;
-use zbus_systemd::systemd1::ManagerProxy;
+use zbus_systemd::systemd1::{ManagerProxy, UnitProxy};
 
 pub struct SystemdContext<'a> {
-    _conn: Connection,
+    conn: Connection,
     manager: ManagerProxy<'a>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct ServiceState {
+    /// e.g. `active`, `inactive` or `failed`
+    pub active: String,
+    /// e.g. `enabled` or `disabled`
+    pub enabled: String,
+}
+
 fn get_unit_name(name: &str) -> String {
     format!("watchers@{name}.service")
 }
 
 impl<'a> SystemdContext<'a> {
     pub async fn new() -> Result<Self> {
-        let conn = Connection::session().await?;
+        let conn = Connection::session()
+            .await
+            .context("Failed to connect to the systemd user session")?;
         let manager = ManagerProxy::new(&conn).await?;
 
-        Ok(Self {
-            _conn: conn,
-            manager,
-        })
+        Ok(Self { conn, manager })
     }
 
     pub async fn stop_and_disable_service(&self, name: &str) -> Result<()> {
@@ -73,6 +81,35 @@ impl<'a> SystemdContext<'a> {
         Ok(())
     }
 
+    pub async fn restart_service(&self, name: &str) -> Result<()> {
+        self.manager
+            .restart_unit(get_unit_name(name), "replace".to_string())
+            .await
+            .context("Failed to restart systemd service")?;
+        Ok(())
+    }
+
+    pub async fn get_service_state(&self, name: &str) -> Result<ServiceState> {
+        let unit_name = get_unit_name(name);
+
+        let unit_path = self
+            .manager
+            .load_unit(unit_name.clone())
+            .await
+            .context("Failed to load systemd unit")?;
+        let unit = UnitProxy::new(&self.conn, unit_path).await?;
+        let active = unit.active_state().await?;
+
+        // instances of a template unit that were never enabled have no unit file state
+        let enabled = self
+            .manager
+            .get_unit_file_state(unit_name)
+            .await
+            .unwrap_or_else(|_| "disabled".to_string());
+
+        Ok(ServiceState { active, enabled })
+    }
+
     pub async fn get_service_logs(&self, name: &str) -> Result<String> {
         let unit_name = format!("watchers@{}.service", name);
         let output = Command::new("journalctl")
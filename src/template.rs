@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+/// Placeholders that can be used inside `commit_message_template`.
+pub const PLACEHOLDERS: &[&str] = &[
+    "summary",
+    "files",
+    "deleted",
+    "modified",
+    "added",
+    "renamed",
+    "deleted_count",
+    "modified_count",
+    "added_count",
+    "renamed_count",
+    "total_count",
+    "watcher",
+    "hostname",
+    "timestamp",
+];
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Var(String),
+    // {?name}...{/name}, only rendered when `name` is non-empty and non-zero
+    Section(String, Vec<Node>),
+}
+
+/// A parsed commit message template.
+///
+/// `{name}` is replaced with the value of a placeholder, `{?name}...{/name}`
+/// only renders its body when the placeholder is non-empty and not `0`, and
+/// `{{` / `}}` produce literal braces.
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+fn check_placeholder(name: &str) -> Result<()> {
+    anyhow::ensure!(
+        PLACEHOLDERS.contains(&name),
+        "Unknown placeholder '{{{}}}' in commit message template",
+        name
+    );
+    Ok(())
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self> {
+        // stack of open sections, the bottom entry is the template root
+        let mut stack: Vec<(Option<String>, Vec<Node>)> = vec![(None, Vec::new())];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut tag = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => tag.push(c),
+                            None => anyhow::bail!("Unclosed '{{' in commit message template"),
+                        }
+                    }
+
+                    let nodes = &mut stack.last_mut().unwrap().1;
+                    if !text.is_empty() {
+                        nodes.push(Node::Text(std::mem::take(&mut text)));
+                    }
+
+                    if let Some(name) = tag.strip_prefix('?') {
+                        check_placeholder(name)?;
+                        stack.push((Some(name.to_string()), Vec::new()));
+                    } else if let Some(name) = tag.strip_prefix('/') {
+                        let (open, body) = stack.pop().unwrap();
+                        anyhow::ensure!(
+                            open.as_deref() == Some(name),
+                            "Unexpected '{{/{}}}' in commit message template",
+                            name
+                        );
+                        stack
+                            .last_mut()
+                            .unwrap()
+                            .1
+                            .push(Node::Section(name.to_string(), body));
+                    } else {
+                        check_placeholder(&tag)?;
+                        nodes.push(Node::Var(tag));
+                    }
+                }
+                '}' => anyhow::bail!("Unmatched '}}' in commit message template"),
+                c => text.push(c),
+            }
+        }
+
+        if let (Some(open), _) = stack.last().unwrap() {
+            anyhow::bail!("Unclosed '{{?{}}}' in commit message template", open);
+        }
+
+        let mut nodes = stack.pop().unwrap().1;
+        if !text.is_empty() {
+            nodes.push(Node::Text(text));
+        }
+
+        Ok(Self { nodes })
+    }
+
+    pub fn render(&self, vars: &HashMap<&str, String>) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, vars, &mut out);
+        out
+    }
+}
+
+fn render_nodes(nodes: &[Node], vars: &HashMap<&str, String>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(name) => {
+                if let Some(value) = vars.get(name.as_str()) {
+                    out.push_str(value);
+                }
+            }
+            Node::Section(name, body) => {
+                if vars
+                    .get(name.as_str())
+                    .is_some_and(|v| !v.is_empty() && v != "0")
+                {
+                    render_nodes(body, vars, out);
+                }
+            }
+        }
+    }
+}
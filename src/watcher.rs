@@ -1,45 +1,305 @@
 use crate::{
-    config::{Config, get_watchers_config_dir},
+    config::{
+        Config, DEFAULTS_FILE_NAMES, MissingRepoAction, NewConfigFile, get_watchers_config_dir,
+    },
+    control::{self, DaemonStatus, Request, Response, send_request, unix_secs},
     debouncer::Debouncer,
+    discover::{find_enclosing_repo, find_repos, is_repo_root},
     file_utils::was_modification,
-    git::{EventContext, handle_event, is_git_ignored},
-    systemd::SystemdContext,
+    filter::PathFilter,
+    git::{
+        EventContext, IgnoreMatcher, RepoStatus, SquashOptions, SquashResult, clone_repo,
+        commit_changes, get_repo_status, is_repo, prepare_repos, squash_autosaves,
+    },
+    network,
+    output::ErrorCode,
+    pause::PauseState,
+    push_queue::PushQueue,
+    state::{CommitRecord, WatcherState},
+    systemd::{ServiceState, SystemdContext},
 };
 
 use anyhow::{Context, Result};
 use inquire::{Confirm, Text};
-use log::debug;
+use log::{debug, error, info};
 use notify::{Event, RecursiveMode};
+use serde::Serialize;
 use std::{
+    collections::BTreeMap,
+    fmt,
     fs::{self},
+    io::{IsTerminal, Write},
     path::{Path, PathBuf},
-    sync::mpsc,
     time::Duration,
 };
+use tokio::sync::{mpsc, oneshot};
 
-pub struct Watcher<'a, F> {
-    pub config: &'a Config,
-    pub debouncer: Debouncer<F>,
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything the watch loop reacts to
+pub enum WatchMessage {
+    Fs(notify::Result<Event>),
+    Control(Request, oneshot::Sender<Response>),
+}
+
+/// One of a watcher's directories. Each has its own debouncer, so changes in
+/// one repository don't hold back commits in another.
+struct WatchedDir {
+    path: PathBuf,
+    debouncer: Debouncer,
+    ignore_matcher: IgnoreMatcher,
+}
+
+impl WatchedDir {
+    fn new<F>(path: &Path, callback: F, config: &Config) -> Self
+    where
+        F: FnMut(EventContext) + Send + 'static,
+    {
+        Self {
+            path: path.to_path_buf(),
+            debouncer: Debouncer::new(
+                callback,
+                config.commit_delay(),
+                config.max_commit_interval(),
+            ),
+            ignore_matcher: IgnoreMatcher::new(path),
+        }
+    }
+}
+
+pub struct Watcher<F> {
+    pub config: Config,
+    pub filter: PathFilter,
+    pub pause: PauseState,
+    dirs: Vec<WatchedDir>,
+    callback: F,
+    tx: mpsc::UnboundedSender<WatchMessage>,
+    rx: mpsc::UnboundedReceiver<WatchMessage>,
 }
 
-impl<'a, F> Watcher<'a, F>
+impl<F> Watcher<F>
 where
-    F: FnMut(EventContext) + Send + 'static,
+    F: FnMut(EventContext) + Clone + Send + 'static,
 {
-    pub fn new(config: &'a Config, debouncer_cb: F) -> Self {
-        let debouncer = Debouncer::new(
-            debouncer_cb,
-            Duration::from_secs(config.commit_delay_secs as u64),
-        );
-        Self { config, debouncer }
+    pub fn new(config: Config, debouncer_cb: F) -> Result<Self> {
+        let dirs = config
+            .repo_dirs()
+            .iter()
+            .map(|path| WatchedDir::new(path, debouncer_cb.clone(), &config))
+            .collect();
+        let filter = config.path_filter()?;
+        let pause = PauseState::load(&config.name);
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            config,
+            filter,
+            pause,
+            dirs,
+            callback: debouncer_cb,
+            tx,
+            rx,
+        })
+    }
+
+    /// Sender for messages into the watch loop, used by the control socket
+    pub fn sender(&self) -> mpsc::UnboundedSender<WatchMessage> {
+        self.tx.clone()
     }
 
-    pub fn trigger_debouncer(&mut self) {
-        let context = EventContext {
-            repo_path: self.config.watch_dir.clone(),
+    fn event_context(&self, dir: &WatchedDir) -> EventContext {
+        EventContext {
+            repo_path: dir.path.clone(),
             config: self.config.clone(),
-        };
-        self.debouncer.on_event(context);
+        }
+    }
+
+    /// Contexts for the watched repositories in `paths`
+    pub fn contexts_for(&self, paths: &[PathBuf]) -> Vec<EventContext> {
+        self.dirs
+            .iter()
+            .filter(|dir| paths.contains(&dir.path))
+            .map(|dir| self.event_context(dir))
+            .collect()
+    }
+
+    /// Index of the directory `path` belongs to, the innermost one if they're nested
+    fn dir_index(&self, path: &Path) -> Option<usize> {
+        self.dirs
+            .iter()
+            .enumerate()
+            .filter(|(_, dir)| path.starts_with(&dir.path))
+            .max_by_key(|(_, dir)| dir.path.components().count())
+            .map(|(i, _)| i)
+    }
+
+    /// Picks up repositories created or moved beneath a watched directory and
+    /// forgets ones that were removed, when `discover_repos` is set
+    fn discover_repos(&mut self, ev: &Event) {
+        if !self.config.discover_repos {
+            return;
+        }
+
+        if ev.kind.is_remove() {
+            self.dirs.retain(|dir| {
+                let exists = is_repo_root(&dir.path);
+                if !exists {
+                    info!(
+                        "Repository {:?} was removed, no longer committing to it",
+                        dir.path
+                    );
+                }
+                exists
+            });
+        }
+
+        let mut found = Vec::new();
+        for path in &ev.paths {
+            let Some(root) = self
+                .config
+                .watch_dirs
+                .iter()
+                .find(|root| path.starts_with(root))
+            else {
+                continue;
+            };
+
+            if let Some(repo) = find_enclosing_repo(root, path) {
+                found.push(repo);
+            } else if path.is_dir() {
+                // e.g. a folder of repositories moved in at once
+                found.extend(find_repos(path));
+            }
+        }
+
+        for repo in found {
+            // already watched, or nested inside a watched repository
+            if self.dirs.iter().any(|dir| repo.starts_with(&dir.path)) {
+                continue;
+            }
+            info!("Found repository {:?}", repo);
+            self.dirs
+                .push(WatchedDir::new(&repo, self.callback.clone(), &self.config));
+        }
+    }
+
+    /// Starts the commit delay of every directory with a relevant change in `ev`
+    fn on_modification(&mut self, ev: &Event) {
+        let changed = (0..self.dirs.len())
+            .filter(|&i| {
+                let dir = &self.dirs[i];
+                let paths = ev
+                    .paths
+                    .iter()
+                    .filter(|p| self.dir_index(p) == Some(i))
+                    .collect::<Vec<_>>();
+
+                !paths.is_empty()
+                    && self.filter.matches_any(&dir.path, &paths)
+                    && !dir.ignore_matcher.is_any_ignored(&paths)
+            })
+            .collect::<Vec<_>>();
+
+        for i in changed {
+            if self.pause.is_paused() {
+                debug!("got valid modification while paused: {:?}", ev);
+            } else {
+                debug!("got valid modification: {:?} - triggering debouncer", ev);
+                let context = self.event_context(&self.dirs[i]);
+                self.dirs[i].debouncer.on_event(context);
+            }
+        }
+    }
+
+    fn status(&self) -> DaemonStatus {
+        DaemonStatus {
+            name: self.config.name.clone(),
+            watch_dirs: self.config.watch_dirs.clone(),
+            paused: self.pause.is_paused(),
+            paused_until: self.pause.until().and_then(unix_secs),
+            commit_pending: self.dirs.iter().any(|dir| dir.debouncer.is_pending()),
+        }
+    }
+
+    /// Re-reads the config file and applies it without restarting the daemon
+    fn reload_config(&mut self, fs_watcher: &mut impl notify::Watcher) -> Result<()> {
+        let config = get_watcher_config(&self.config.name)?;
+        let filter = config.path_filter()?;
+
+        let added_roots = config
+            .watch_dirs
+            .iter()
+            .filter(|path| !self.config.watch_dirs.contains(path))
+            .collect::<Vec<_>>();
+        for path in &added_roots {
+            fs_watcher.watch(path, RecursiveMode::Recursive)?;
+        }
+        for path in &self.config.watch_dirs {
+            if !config.watch_dirs.contains(path)
+                && let Err(e) = fs_watcher.unwatch(path)
+            {
+                error!("Failed to stop watching {:?}: {}", path, e);
+            }
+        }
+
+        // pending changes in removed directories are dropped along with their debouncer
+        let repo_dirs = config.repo_dirs();
+        self.dirs.retain(|dir| repo_dirs.contains(&dir.path));
+        for path in &repo_dirs {
+            if !self.dirs.iter().any(|dir| &dir.path == path) {
+                self.dirs
+                    .push(WatchedDir::new(path, self.callback.clone(), &config));
+            }
+        }
+
+        for dir in &self.dirs {
+            dir.debouncer
+                .set_timing(config.commit_delay(), config.max_commit_interval());
+        }
+        self.filter = filter;
+        self.config = config;
+        Ok(())
+    }
+
+    async fn handle_request(
+        &mut self,
+        request: Request,
+        fs_watcher: &mut impl notify::Watcher,
+    ) -> Response {
+        match request {
+            Request::Trigger => {
+                trigger_watcher(self).await;
+                Response::Ok
+            }
+            Request::Pause { duration_secs } => {
+                self.pause.pause(duration_secs.map(Duration::from_secs));
+                // don't let an already running timer commit while paused
+                for dir in &self.dirs {
+                    dir.debouncer.cancel();
+                }
+                match self.pause.save(&self.config.name) {
+                    Ok(()) => Response::Ok,
+                    Err(e) => Response::error(e),
+                }
+            }
+            Request::Resume => {
+                if !self.pause.is_paused() {
+                    return Response::Ok;
+                }
+                self.pause.resume();
+                let saved = self.pause.save(&self.config.name);
+                debug!("watcher resumed - committing pending changes");
+                trigger_watcher(self).await;
+                match saved {
+                    Ok(()) => Response::Ok,
+                    Err(e) => Response::error(e),
+                }
+            }
+            Request::Status => Response::Status(self.status()),
+            Request::ReloadConfig => match self.reload_config(fs_watcher) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::error(e),
+            },
+        }
     }
 }
 
@@ -55,51 +315,108 @@ fn is_git_file(paths: &[impl AsRef<Path>]) -> Result<bool> {
         .any(|p| p.as_ref().components().any(|c| c.as_os_str() == ".git")))
 }
 
-pub fn watch_repo<F>(watcher: &mut Watcher<F>) -> Result<()>
+pub async fn watch_repo<F>(watcher: &mut Watcher<F>) -> Result<()>
 where
-    F: FnMut(EventContext) + Send + 'static,
+    F: FnMut(EventContext) + Clone + Send + 'static,
 {
-    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
-    let mut fs_watcher = notify::recommended_watcher(tx)?;
-    notify::Watcher::watch(
-        &mut fs_watcher,
-        &watcher.config.watch_dir,
-        RecursiveMode::Recursive,
-    )?;
-
-    // run callback initially to commit any preexisting changes
+    let fs_tx = watcher.sender();
+    let mut fs_watcher = notify::recommended_watcher(move |ev| {
+        let _ = fs_tx.send(WatchMessage::Fs(ev));
+    })?;
+    for dir in &watcher.config.watch_dirs {
+        notify::Watcher::watch(&mut fs_watcher, dir, RecursiveMode::Recursive)?;
+    }
+
+    // watch the directory rather than the file, editors often save by replacing it
+    let config_path = Config::get_watcher_config_path(&watcher.config.name);
+    if let Some(config_dir) = config_path.parent() {
+        notify::Watcher::watch(&mut fs_watcher, config_dir, RecursiveMode::NonRecursive)?;
+    }
+
+    let mut pause_check = tokio::time::interval(PAUSE_CHECK_INTERVAL);
     loop {
-        match rx.recv() {
-            Err(e) => println!("watch error: {:?}", e),
-            Ok(ev) => {
+        // wake up regularly to notice a pause running out. The watcher holds a
+        // sender itself, so the channel never closes.
+        let message = tokio::select! {
+            message = watcher.rx.recv() => message,
+            _ = pause_check.tick() => None,
+        };
+
+        match message {
+            None => (),
+            Some(WatchMessage::Control(request, reply)) => {
+                let response = watcher.handle_request(request, &mut fs_watcher).await;
+                let _ = reply.send(response);
+            }
+            Some(WatchMessage::Fs(ev)) => {
+                if let Ok(ev) = &ev {
+                    watcher.discover_repos(ev);
+                    for dir in &watcher.dirs {
+                        dir.ignore_matcher.on_paths_changed(&ev.paths);
+                    }
+
+                    if was_modification(ev)
+                        && ev.paths.iter().any(|p| is_config_file(p, &config_path))
+                    {
+                        match watcher.reload_config(&mut fs_watcher) {
+                            Ok(()) => info!("Reloaded config from {}", config_path.display()),
+                            Err(e) => error!("Invalid config, keeping the previous one: {:#}", e),
+                        }
+                    }
+                }
+
                 if let Ok(ev) = ev
                     && was_modification(&ev)
                     && !is_git_file(&ev.paths)?
-                    && !is_git_ignored(&ev.paths)?
                 {
-                    debug!("got valid modification: {:?} - triggering debouncer", ev);
-                    watcher.trigger_debouncer();
+                    watcher.on_modification(&ev);
                 }
             }
         }
+
+        // commit whatever changed while paused once the pause runs out
+        if watcher.pause.expire() {
+            debug!("pause expired - committing pending changes");
+            if let Err(e) = watcher.pause.save(&watcher.config.name) {
+                error!("Failed to remove the pause: {}", e);
+            }
+            trigger_watcher(watcher).await;
+        }
     }
 }
 
-pub fn trigger_watcher<F>(watcher: &mut Watcher<F>)
+/// Whether `path` is the watcher's config file or the defaults file it inherits from
+fn is_config_file(path: &Path, config_path: &Path) -> bool {
+    path == config_path
+        || path.parent() == config_path.parent()
+            && path.extension().is_some_and(|ext| ext == "yml")
+            && path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .is_some_and(|stem| DEFAULTS_FILE_NAMES.contains(&stem))
+}
+
+/// Commits pending changes in every directory right away and waits for the commits
+pub async fn trigger_watcher<F>(watcher: &Watcher<F>)
 where
-    F: FnMut(EventContext) + Send + 'static,
+    F: FnMut(EventContext) + Clone + Send + 'static,
 {
-    watcher.debouncer.run_callback(EventContext {
-        repo_path: watcher.config.watch_dir.clone(),
-        config: watcher.config.clone(),
-    });
+    let done = watcher
+        .dirs
+        .iter()
+        .map(|dir| dir.debouncer.run_callback(watcher.event_context(dir)))
+        .collect::<Vec<_>>();
+    for done in done {
+        let _ = done.await;
+    }
 }
 
-
 pub fn get_watcher_config(name: &str) -> Result<Config> {
     let path = Config::get_watcher_config_path(name);
-    anyhow::ensure!(path.is_file(), "Could not find config for '{}'", name);
-    Config::from_file(path)
+    if !path.is_file() {
+        return Err(ErrorCode::NotFound.err(format!("Could not find config for '{}'", name)));
+    }
+    Config::from_file(path).map_err(|e| ErrorCode::InvalidConfig.err(format!("{:#}", e)))
 }
 
 pub async fn start_watcher(name: &str) -> Result<()> {
@@ -109,23 +426,108 @@ pub async fn start_watcher(name: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn create_watcher(name: &str) -> Result<()> {
-    let path_input = Text::new("Path to directory to watch:")
-        .prompt()
-        .context("Failed to read input")?;
-    let path = PathBuf::from(shellexpand::tilde(&path_input).to_string());
+/// Settings for `create_watcher`. Missing values are prompted for when stdin
+/// is a terminal.
+#[derive(Debug, Default)]
+pub struct CreateOptions {
+    pub paths: Vec<PathBuf>,
+    pub delay_secs: Option<u32>,
+    /// Allow creating repositories in directories that aren't one
+    pub init: bool,
+    /// Clone this into the directory if it isn't a repository
+    pub clone_url: Option<String>,
+    pub no_push: bool,
+    pub force: bool,
+    pub no_start: bool,
+}
+
+pub async fn create_watcher(name: &str, options: &CreateOptions) -> Result<()> {
+    if DEFAULTS_FILE_NAMES.contains(&name) {
+        return Err(ErrorCode::InvalidInput.err(format!(
+            "'{}' is reserved for the defaults file, pick another name",
+            name
+        )));
+    }
 
-    anyhow::ensure!(
-        path.is_dir(),
-        "Path '{}' is not a directory",
-        path.display()
-    );
+    let interactive = std::io::stdin().is_terminal();
+
+    let mut paths = options
+        .paths
+        .iter()
+        .map(|path| PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).to_string()))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        if !interactive {
+            return Err(
+                ErrorCode::InvalidInput.err("--path is required when stdin isn't a terminal")
+            );
+        }
+        let path_input = Text::new("Path to directory to watch:")
+            .prompt()
+            .context("Failed to read input")?;
+        paths.push(PathBuf::from(shellexpand::tilde(&path_input).to_string()));
+    }
+
+    if options.clone_url.is_some() && paths.len() != 1 {
+        return Err(ErrorCode::InvalidInput.err("--clone-url needs exactly one --path"));
+    }
 
-    let config = Config::new(name, &path);
+    // the directory to clone into doesn't have to exist yet
+    if let Some(path) = paths
+        .iter()
+        .find(|path| !path.is_dir() && (options.clone_url.is_none() || path.exists()))
+    {
+        return Err(
+            ErrorCode::InvalidInput.err(format!("Path '{}' is not a directory", path.display()))
+        );
+    }
+
+    let mut on_missing_repo = if options.clone_url.is_some() {
+        Some(MissingRepoAction::Clone)
+    } else if options.init {
+        Some(MissingRepoAction::Init)
+    } else {
+        None
+    };
+
+    // don't silently turn e.g. a home directory into a repository
+    if let Some(path) = paths.iter().find(|path| !is_repo(path))
+        && on_missing_repo.is_none()
+    {
+        let init = interactive
+            && Confirm::new(&format!(
+                "'{}' isn't a git repository, create one when the first change is made?",
+                path.display()
+            ))
+            .with_default(false)
+            .prompt()?;
+        if !init {
+            return Err(ErrorCode::InvalidInput.err(format!(
+                "'{}' isn't a git repository, pass --init to create one or --clone-url to clone one",
+                path.display()
+            )));
+        }
+        on_missing_repo = Some(MissingRepoAction::Init);
+    }
+
+    // a single directory keeps the simpler `watch_dir` form
+    let (watch_dir, watch_dirs) = if paths.len() == 1 {
+        (paths.pop(), Vec::new())
+    } else {
+        (None, paths)
+    };
+    let config = NewConfigFile {
+        watch_dir,
+        watch_dirs,
+        on_missing_repo,
+        clone_url: options.clone_url.clone(),
+        commit_delay_secs: options.delay_secs,
+        auto_push: options.no_push.then_some(false),
+    };
     let config_path = Config::get_watcher_config_path(name);
 
     let mut should_overwrite_config: bool = true;
-    if config_path.exists() {
+    if config_path.exists() && !options.force {
         anyhow::ensure!(
             config_path.is_file(),
             format!(
@@ -134,33 +536,158 @@ pub async fn create_watcher(name: &str) -> Result<()> {
             )
         );
 
-        should_overwrite_config = Confirm::new(&format!(
-            "'{}' already exists, do you want to overwrite it?",
-            config_path.as_os_str().display()
-        ))
-        .with_default(false)
-        .prompt()?;
+        should_overwrite_config = interactive
+            && Confirm::new(&format!(
+                "'{}' already exists, do you want to overwrite it?",
+                config_path.as_os_str().display()
+            ))
+            .with_default(false)
+            .prompt()?;
     }
 
     if !should_overwrite_config {
-        return Ok(());
+        return Err(ErrorCode::AlreadyExists.err(format!("Watcher '{}' already exists", name)));
+    }
+
+    // last, so nothing is cloned for a watcher that isn't created
+    if let Some(url) = &config.clone_url
+        && let Some(path) = &config.watch_dir
+        && !is_repo(path)
+    {
+        clone_repo(url, path)?;
     }
 
     if let Some(parent) = &config_path.parent() {
         fs::create_dir_all(parent)?;
     }
 
-    fs::write(&config_path, config.dump()?).context(format!(
+    fs::write(&config_path, serde_yaml::to_string(&config)?).context(format!(
         "Failed to write config to: {}",
         &config_path.as_os_str().display()
     ))?;
 
-    let systemd_ctx = SystemdContext::new().await?;
-    systemd_ctx.start_and_enable_service(name).await?;
+    if !options.no_start {
+        let systemd_ctx = SystemdContext::new().await?;
+        systemd_ctx.start_and_enable_service(name).await?;
+    }
 
     Ok(())
 }
 
+/// What happened after `edit_watcher` saved the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditOutcome {
+    Unchanged,
+    /// Saved, the watcher isn't running
+    Saved,
+    Reloaded,
+    Restarted,
+}
+
+fn run_editor(path: &Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or("vi".to_string());
+
+    // through the shell so $EDITOR can contain arguments, e.g. `code --wait`
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor '{}'", editor))?;
+    anyhow::ensure!(
+        status.success(),
+        "Editor '{}' exited with {}",
+        editor,
+        status
+    );
+    Ok(())
+}
+
+/// Lets the user edit a watcher's config in `$EDITOR` until it is valid, then
+/// replaces the config and applies it to the running watcher.
+pub async fn edit_watcher(name: &str) -> Result<EditOutcome> {
+    let config_path = Config::get_watcher_config_path(name);
+    if !config_path.is_file() {
+        return Err(ErrorCode::NotFound.err(format!("Could not find config for '{}'", name)));
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(ErrorCode::InvalidInput.err("Editing a config needs a terminal"));
+    }
+
+    let original = fs::read_to_string(&config_path)?;
+    // edit a copy outside the config directory so the watcher doesn't see half-saved files
+    let copy = tempfile::Builder::new()
+        .prefix(&format!("{}-", name))
+        .suffix(".yml")
+        .tempfile()?;
+    fs::write(copy.path(), &original)?;
+
+    let content = loop {
+        run_editor(copy.path())?;
+
+        let problems = match Config::load_as(name, copy.path()) {
+            Ok((_, unknown_keys)) => unknown_keys
+                .iter()
+                .map(|key| format!("Unknown key '{}'", key))
+                .collect::<Vec<_>>(),
+            Err(e) => vec![format!("{:#}", e)],
+        };
+        if problems.is_empty() {
+            break fs::read_to_string(copy.path())?;
+        }
+
+        eprintln!("The config is invalid:");
+        for problem in &problems {
+            eprintln!("  {}", problem);
+        }
+        let edit_again = Confirm::new("Edit it again?").with_default(true).prompt()?;
+        if !edit_again {
+            return Err(ErrorCode::InvalidConfig.err(format!(
+                "Discarded invalid changes to '{}': {}",
+                name,
+                problems.join(", ")
+            )));
+        }
+    };
+
+    if content == original {
+        return Ok(EditOutcome::Unchanged);
+    }
+
+    // write next to the config and rename over it, so it is never half written
+    let config_dir = config_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Invalid config path"))?;
+    let mut new_file = tempfile::NamedTempFile::new_in(config_dir)?;
+    new_file.write_all(content.as_bytes())?;
+    new_file
+        .persist(&config_path)
+        .with_context(|| format!("Failed to replace {}", config_path.display()))?;
+
+    if !control::is_running(name).await {
+        return Ok(EditOutcome::Saved);
+    }
+
+    let reloaded = send_request(name, &Request::ReloadConfig)
+        .await
+        .and_then(Response::into_result);
+    match reloaded {
+        Ok(_) => Ok(EditOutcome::Reloaded),
+        Err(e) => {
+            debug!(
+                "Failed to reload '{}', restarting it instead: {:#}",
+                name, e
+            );
+            let systemd_ctx = SystemdContext::new().await?;
+            systemd_ctx.restart_service(name).await?;
+            Ok(EditOutcome::Restarted)
+        }
+    }
+}
+
 pub async fn stop_watcher(name: &str) -> Result<()> {
     let systemd_ctx = SystemdContext::new().await?;
     systemd_ctx.stop_and_disable_service(name).await?;
@@ -169,11 +696,15 @@ pub async fn stop_watcher(name: &str) -> Result<()> {
 }
 
 pub async fn delete_watcher(name: &str) -> Result<()> {
-    stop_watcher(name).await?;
-
     let config_path = Config::get_watcher_config_path(name);
-    anyhow::ensure!(config_path.is_file(), "Couldn't find watcher '{}'", name);
+    if !config_path.is_file() {
+        return Err(ErrorCode::NotFound.err(format!("Couldn't find watcher '{}'", name)));
+    }
+
+    stop_watcher(name).await?;
     fs::remove_file(config_path)?;
+    WatcherState::remove(name)?;
+    PauseState::default().save(name)?;
     Ok(())
 }
 
@@ -183,24 +714,166 @@ pub async fn get_watcher_logs(name: &str) -> Result<String> {
     Ok(logs)
 }
 
-pub fn list_watchers() -> Result<()> {
-    println!("Watchers:");
+pub fn get_watcher_names() -> Result<Vec<String>> {
     let config_dir = get_watchers_config_dir();
+    if !config_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
     for file in fs::read_dir(config_dir)? {
         let path = file?.path();
-        if !path.is_file() {
+        // only `<name>.yml` files are watchers, e.g. a schema may live next to them
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != "yml") {
             continue;
         }
 
-        println!(
-            "  {}",
-            path.file_stem()
-                .and_then(|s| s.to_str())
-                .ok_or_else(|| anyhow::anyhow!("Failed to read config directory"))?
-        );
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Failed to read config directory"))?;
+        if !DEFAULTS_FILE_NAMES.contains(&name) {
+            names.push(name.to_string());
+        }
     }
+    names.sort();
 
-    Ok(())
+    Ok(names)
+}
+
+/// Squashes the watcher's autosave commits in every repository it commits to
+pub fn squash_watcher(name: &str, options: &SquashOptions) -> Result<Vec<SquashResult>> {
+    let config = get_watcher_config(name)?;
+    let mut results = Vec::new();
+    for dir in config.repo_dirs().iter().filter(|dir| is_repo(dir)) {
+        let result = squash_autosaves(&config, dir, options)
+            .with_context(|| format!("Failed to squash commits in {}", dir.display()))?;
+        results.push(result);
+    }
+    Ok(results)
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatcherStatus {
+    pub name: String,
+    pub service: Option<ServiceState>,
+    pub repos: Vec<RepoStatus>,
+    pub last_commit: Option<CommitRecord>,
+    pub last_push_error: Option<String>,
+    /// Branches that conflicting commits were pushed to, by repository
+    pub conflict_branches: BTreeMap<PathBuf, String>,
+    pub paused: bool,
+}
+
+pub async fn get_watcher_status(
+    name: &str,
+    systemd_ctx: Option<&SystemdContext<'_>>,
+) -> Result<WatcherStatus> {
+    let config = get_watcher_config(name)?;
+    let state = WatcherState::load(name).unwrap_or_default();
+
+    let service = match systemd_ctx {
+        Some(ctx) => ctx
+            .get_service_state(name)
+            .await
+            .inspect_err(|e| debug!("Failed to get service state for '{}': {}", name, e))
+            .ok(),
+        None => None,
+    };
+
+    let repos = config
+        .repo_dirs()
+        .iter()
+        .map(|dir| {
+            get_repo_status(&config, dir).unwrap_or_else(|e| {
+                debug!("Failed to get status of {:?} for '{}': {}", dir, name, e);
+                RepoStatus {
+                    path: dir.clone(),
+                    pending_changes: None,
+                    ahead: None,
+                    behind: None,
+                }
+            })
+        })
+        .collect();
+
+    let paused = match send_request(name, &Request::Status).await {
+        Ok(Response::Status(status)) => status.paused,
+        // a pause set while the daemon was stopped
+        _ => {
+            let mut pause = PauseState::load(name);
+            pause.expire();
+            pause.is_paused()
+        }
+    };
+
+    Ok(WatcherStatus {
+        name: config.name,
+        service,
+        repos,
+        last_commit: state.last_commit,
+        last_push_error: state.last_push_error,
+        conflict_branches: state.conflict_branches,
+        paused,
+    })
+}
+
+impl fmt::Display for WatcherStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = "unknown".to_string();
+        writeln!(f, "{}", self.name)?;
+
+        match &self.service {
+            Some(service) => {
+                writeln!(f, "  service:     {} ({})", service.active, service.enabled)?
+            }
+            None => writeln!(f, "  service:     {}", unknown)?,
+        }
+        if self.paused {
+            writeln!(f, "  paused:      yes")?;
+        }
+        match &self.last_commit {
+            Some(commit) => {
+                write!(
+                    f,
+                    "  last commit: {} at {}",
+                    &commit.hash[..commit.hash.len().min(7)],
+                    commit.time
+                )?;
+                match &commit.repo {
+                    Some(repo) if self.repos.len() > 1 => writeln!(f, " in {}", repo.display())?,
+                    _ => writeln!(f)?,
+                }
+            }
+            None => writeln!(f, "  last commit: none")?,
+        }
+
+        for repo in &self.repos {
+            writeln!(f, "  {}", repo.path.display())?;
+            writeln!(
+                f,
+                "    pending:   {} changed files",
+                repo.pending_changes
+                    .map(|n| n.to_string())
+                    .unwrap_or(unknown.clone())
+            )?;
+            match (repo.ahead, repo.behind) {
+                (Some(ahead), Some(behind)) => {
+                    writeln!(f, "    upstream:  ahead {}, behind {}", ahead, behind)?
+                }
+                _ => writeln!(f, "    upstream:  none")?,
+            }
+            if let Some(branch) = self.conflict_branches.get(&repo.path) {
+                writeln!(f, "    conflict:  pushed to {}", branch)?;
+            }
+        }
+
+        if let Some(e) = &self.last_push_error {
+            writeln!(f, "  push error:  {}", e)?;
+        }
+
+        Ok(())
+    }
 }
 
 pub async fn run_daemon(name: &str) -> Result<()> {
@@ -209,12 +882,42 @@ pub async fn run_daemon(name: &str) -> Result<()> {
         "Config:\n{}",
         config.dump().unwrap_or("failed to read config".to_string())
     );
+    prepare_repos(&config)?;
+
+    let pushes = PushQueue::new();
+    let commit_pushes = pushes.clone();
+    let mut watcher = Watcher::new(config, move |context| {
+        if commit_changes(&context) {
+            commit_pushes.request(context);
+        }
+    })?;
+
+    // commits a previous run couldn't push
+    let outbox = WatcherState::load(name).unwrap_or_default().needs_push;
+    for context in watcher.contexts_for(&outbox) {
+        info!("Pushing {} from the outbox", context.repo_path.display());
+        pushes.request(context);
+    }
 
-    let mut watcher = Watcher::new(&config, |context| {
-        handle_event(context);
+    tokio::spawn(async move {
+        let on_up = || {
+            info!("Network is up, retrying failed pushes");
+            pushes.retry_now();
+        };
+        if let Err(e) = network::watch_network(on_up).await {
+            debug!("Not retrying pushes on network changes: {:#}", e);
+        }
+    });
+
+    let control_tx = watcher.sender();
+    let control_name = name.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(&control_name, control_tx).await {
+            error!("Control socket stopped: {}", e);
+        }
     });
 
-    watch_repo(&mut watcher)?;
+    watch_repo(&mut watcher).await?;
 
     anyhow::bail!("Should never finish watching");
 }